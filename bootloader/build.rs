@@ -0,0 +1,30 @@
+//! Copies `memory.x` to where the linker can find it, see the application's `build.rs`.
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+fn main() {
+    let pico2_w = env::var_os("CARGO_FEATURE_PICO2_W").is_some();
+    let memory: &[u8] = if pico2_w {
+        include_bytes!("memory-pico2-w.x")
+    } else {
+        include_bytes!("memory.x")
+    };
+
+    let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    File::create(out.join("memory.x"))
+        .unwrap()
+        .write_all(memory)
+        .unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory-pico2-w.x");
+
+    println!("cargo:rustc-link-arg-bins=--nmagic");
+    println!("cargo:rustc-link-arg-bins=-Tlink.x");
+    if !pico2_w {
+        println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
+    }
+}
@@ -0,0 +1,41 @@
+MEMORY
+{
+  FLASH : ORIGIN = 0x10000000, LENGTH = 24K
+  /* Must match the application's `memory-pico2-w.x` */
+  BOOTLOADER_STATE : ORIGIN = 0x10006000, LENGTH = 4K
+  ACTIVE : ORIGIN = 0x10007000, LENGTH = 872K
+  DFU : ORIGIN = 0x100E1000, LENGTH = 876K
+  /* Left untouched for the application's crash report and DHCP lease, must match its `memory-pico2-w.x` */
+  RAM : ORIGIN = 0x20000800, LENGTH = 510K
+}
+
+SECTIONS {
+  /* The boot rom looks for the image definition within the first 4K of flash */
+  .start_block : ALIGN(4)
+  {
+    __start_block_addr = .;
+    KEEP(*(.start_block));
+  } > FLASH
+} INSERT AFTER .vector_table;
+
+_stext = ADDR(.start_block) + SIZEOF(.start_block);
+
+SECTIONS {
+  .end_block : ALIGN(4)
+  {
+    __end_block_addr = .;
+    KEEP(*(.end_block));
+  } > FLASH
+} INSERT AFTER .uninit;
+
+PROVIDE(start_to_end = __end_block_addr - __start_block_addr);
+PROVIDE(end_to_start = __start_block_addr - __end_block_addr);
+
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(FLASH);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(FLASH);
+
+__bootloader_active_start = ORIGIN(ACTIVE) - ORIGIN(FLASH);
+__bootloader_active_end = ORIGIN(ACTIVE) + LENGTH(ACTIVE) - ORIGIN(FLASH);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(FLASH);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(FLASH);
@@ -0,0 +1,20 @@
+MEMORY
+{
+  BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
+  FLASH : ORIGIN = 0x10000100, LENGTH = 24K - 0x100
+  /* Must match the application's `memory.x` */
+  BOOTLOADER_STATE : ORIGIN = 0x10006000, LENGTH = 4K
+  ACTIVE : ORIGIN = 0x10007000, LENGTH = 872K
+  DFU : ORIGIN = 0x100E1000, LENGTH = 876K
+  /* Left untouched for the application's crash report and DHCP lease, must match its `memory.x` */
+  RAM : ORIGIN = 0x20000800, LENGTH = 262K
+}
+
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+
+__bootloader_active_start = ORIGIN(ACTIVE) - ORIGIN(BOOT2);
+__bootloader_active_end = ORIGIN(ACTIVE) + LENGTH(ACTIVE) - ORIGIN(BOOT2);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOT2);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOT2);
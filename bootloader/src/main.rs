@@ -0,0 +1,50 @@
+//! Swaps in updates written by `src/ota.rs` before booting the application, reverting them if they aren't confirmed.
+
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+
+use cortex_m_rt::{entry, exception};
+use embassy_boot_rp::{BootLoader, BootLoaderConfig};
+use embassy_rp::flash::{Blocking, Flash, FLASH_BASE};
+use embassy_sync::blocking_mutex::Mutex;
+
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// Marks the bootloader as an executable for the RP2350 boot rom.
+#[cfg(feature = "pico2-w")]
+#[link_section = ".start_block"]
+#[used]
+pub static IMAGE_DEF: embassy_rp::block::ImageDef = embassy_rp::block::ImageDef::secure_exe();
+
+#[entry]
+fn main() -> ! {
+    let peripherals = embassy_rp::init(embassy_rp::config::Config::default());
+
+    let flash = Flash::<_, Blocking, FLASH_SIZE>::new_blocking(peripherals.FLASH);
+    let flash = Mutex::new(RefCell::new(flash));
+
+    let config = BootLoaderConfig::from_linkerfile_blocking(&flash, &flash, &flash);
+    let active_offset = config.active.offset();
+    let bootloader: BootLoader = BootLoader::prepare(config);
+
+    let start = FLASH_BASE as u32 + active_offset;
+    unsafe { bootloader.load(start) }
+}
+
+#[no_mangle]
+#[cfg_attr(target_os = "none", link_section = ".HardFault.user")]
+unsafe extern "C" fn HardFault() {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[exception]
+unsafe fn DefaultHandler(_: i16) -> ! {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    cortex_m::asm::udf();
+}
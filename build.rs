@@ -9,17 +9,26 @@
 //! new memory settings.
 
 use std::env;
-use std::fs::File;
+use std::fmt::Write as _;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
 
 fn main() {
+    // Each board has its own layout, `memory.x` is the Pico W's.
+    let pico2_w = env::var_os("CARGO_FEATURE_PICO2_W").is_some();
+    let memory: &[u8] = if pico2_w {
+        include_bytes!("memory-pico2-w.x")
+    } else {
+        include_bytes!("memory.x")
+    };
+
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
@@ -28,9 +37,111 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory-pico2-w.x");
+
+    // Embed the key used to verify over-the-air updates, see `src/ota.rs`.
+    let public_key = env::var_os("OTA_PUBLIC_KEY").map(|path| {
+        let key = fs::read(&path).expect("Failed to read `OTA_PUBLIC_KEY`");
+        assert_eq!(
+            key.len(),
+            32,
+            "`OTA_PUBLIC_KEY` must be a raw 32 byte ed25519 key"
+        );
+        println!("cargo:rerun-if-changed={}", path.to_string_lossy());
+        key
+    });
+    File::create(out.join("ota_public_key.rs"))
+        .unwrap()
+        .write_all(format!("const PUBLIC_KEY: Option<[u8; 32]> = {public_key:?};\n").as_bytes())
+        .unwrap();
+    println!("cargo:rerun-if-env-changed=OTA_PUBLIC_KEY");
+
+    // Where crash reports are uploaded to, see `src/system/crash.rs`.
+    let report_url = env::var("CRASH_REPORT_URL").ok();
+    File::create(out.join("crash_report_url.rs"))
+        .unwrap()
+        .write_all(format!("const REPORT_URL: Option<&str> = {report_url:?};\n").as_bytes())
+        .unwrap();
+    println!("cargo:rerun-if-env-changed=CRASH_REPORT_URL");
+
+    // The size of the heap in bytes with the `alloc` feature, see `src/allocator.rs`.
+    let heap_size = env::var("HEAP_SIZE").map_or(32 * 1024, |size| {
+        size.parse::<usize>()
+            .expect("`HEAP_SIZE` must be a number of bytes")
+    });
+    File::create(out.join("heap_size.rs"))
+        .unwrap()
+        .write_all(format!("const HEAP_SIZE: usize = {heap_size};\n").as_bytes())
+        .unwrap();
+    println!("cargo:rerun-if-env-changed=HEAP_SIZE");
+
+    // The identity the device presents over USB, see `src/serial.rs`.
+    let mut usb_identity = String::new();
+    for (name, default) in [("USB_VID", 0xc0de), ("USB_PID", 0xcafe)] {
+        let id = env::var(name).map_or(default, |id| {
+            u16::from_str_radix(id.trim_start_matches("0x"), 16)
+                .unwrap_or_else(|_| panic!("`{name}` must be a 16 bit hex number"))
+        });
+        writeln!(usb_identity, "const {name}: u16 = {id:#06x};").unwrap();
+        println!("cargo:rerun-if-env-changed={name}");
+    }
+    for (name, default) in [
+        ("USB_MANUFACTURER", "AestheticSkye"),
+        ("USB_PRODUCT", "IoT Device"),
+    ] {
+        let value = env::var(name).unwrap_or_else(|_| default.to_owned());
+        writeln!(usb_identity, "const {name}: &str = {value:?};").unwrap();
+        println!("cargo:rerun-if-env-changed={name}");
+    }
+    File::create(out.join("usb_identity.rs"))
+        .unwrap()
+        .write_all(usb_identity.as_bytes())
+        .unwrap();
+
+    // Record the CYW43 blobs so preflashed copies can be checked, see `src/networking/firmware.rs`.
+    let mut firmware = String::new();
+    for (name, path) in [
+        ("FIRMWARE", "firmware/43439A0.bin"),
+        ("CLM", "firmware/43439A0_clm.bin"),
+    ] {
+        let blob = fs::read(path).unwrap();
+        writeln!(firmware, "const {name}_LEN: usize = {};", blob.len()).unwrap();
+        writeln!(
+            firmware,
+            "const {name}_CHECKSUM: u32 = {:#x};",
+            fnv1a(&blob)
+        )
+        .unwrap();
+        println!("cargo:rerun-if-changed={path}");
+    }
+    File::create(out.join("cyw43_firmware.rs"))
+        .unwrap()
+        .write_all(firmware.as_bytes())
+        .unwrap();
+
+    // The size of the executor's task arena, for memory.x to check it found the arena, see `src/system/memory.rs`.
+    let manifest = fs::read_to_string("Cargo.toml").unwrap();
+    let task_arena_size = manifest
+        .split("\"task-arena-size-")
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .and_then(|size| size.parse::<usize>().ok())
+        .expect("embassy-executor must have a `task-arena-size-*` feature");
+    println!("cargo:rustc-link-arg-bins=--defsym=__task_arena_size={task_arena_size}");
+    println!("cargo:rerun-if-changed=Cargo.toml");
 
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
-    println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
+    // Only the RP2040 has a second stage bootloader to link.
+    if !pico2_w {
+        println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
+    }
+}
+
+/// Must match `fnv1a` in `src/storage.rs`.
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    })
 }
@@ -1,6 +1,57 @@
 MEMORY
 {
   BOOT2   : ORIGIN = 0x10000000, LENGTH = 0x100
-  FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
-  RAM : ORIGIN = 0x20000000, LENGTH = 264K
+  /* The bootloader in `bootloader/` lives between BOOT2 and BOOTLOADER_STATE */
+  BOOTLOADER_STATE : ORIGIN = 0x10006000, LENGTH = 4K
+  FLASH : ORIGIN = 0x10007000, LENGTH = 872K
+  /* Update slot, see `src/ota.rs`, must be one page larger than FLASH */
+  DFU : ORIGIN = 0x100E1000, LENGTH = 876K
+  /* Preflashed firmware and CLM blobs, see `src/networking/firmware.rs` */
+  CYW43_FIRMWARE : ORIGIN = 0x101BC000, LENGTH = 256K
+  /* Persistent records, see `src/storage.rs` */
+  STORAGE : ORIGIN = 0x101FC000, LENGTH = 16K
+  /* Crash reports kept across resets, see `src/system/crash.rs`, the boot ROM uses the top of RAM */
+  CRASH_REPORT : ORIGIN = 0x20000000, LENGTH = 1K
+  /* The DHCP lease kept across the resets sleeps end with, see `src/networking/lease.rs` */
+  LEASE : ORIGIN = 0x20000400, LENGTH = 1K
+  RAM : ORIGIN = 0x20000800, LENGTH = 262K
 }
+
+SECTIONS {
+  /* Not zeroed on boot, so a crash report survives the reset */
+  .crash_report (NOLOAD) : ALIGN(4)
+  {
+    KEEP(*(.crash_report));
+  } > CRASH_REPORT
+
+  /* Not zeroed on boot either, so the lease survives the reset */
+  .lease (NOLOAD) : ALIGN(4)
+  {
+    KEEP(*(.lease));
+  } > LEASE
+
+  /* The heap when the `alloc` feature is enabled, see `src/allocator.rs` */
+  .heap (NOLOAD) : ALIGN(8)
+  {
+    KEEP(*(.heap));
+  } > RAM
+} INSERT AFTER .uninit;
+
+/* The main stack grows down towards the heap */
+__stack_limit = ADDR(.heap) + SIZEOF(.heap);
+
+SECTIONS {
+  /* The executor's task arena, taken out of .bss to measure its usage, see `src/system/memory.rs` */
+  .task_arena (NOLOAD) : ALIGN(4)
+  {
+    __task_arena_start = .;
+    KEEP(*(.bss._ZN16embassy_executor7_export5ARENA*));
+    . = ALIGN(4);
+    __task_arena_end = .;
+  } > RAM
+} INSERT BEFORE .bss;
+
+/* The arena is its buffer, sized by build.rs from embassy-executor's features, then its bump pointer. Anything else
+   means the symbol was renamed and the arena was left in .bss */
+ASSERT(__task_arena_end - __task_arena_start == __task_arena_size + 4,
+  "The executor's task arena wasn't found by its symbol name, see .task_arena");
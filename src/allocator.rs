@@ -1,12 +1,74 @@
-// use core::mem::MaybeUninit;
-// use embedded_alloc::LlffHeap;
+//! The global heap, enabled with the `alloc` feature.
+//!
+//! The heap is `HEAP_SIZE` bytes, 32K unless set at build time, in its own `.heap` section of RAM. Allocations that
+//! don't fit are counted and logged, then `alloc`'s error handler panics, which leaves a crash report rather than
+//! faulting, see [`crate::system::crash`].
 
-// #[global_allocator]
-// static HEAP: LlffHeap = LlffHeap::empty();
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::MaybeUninit;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::Ordering;
 
-// const HEAP_SIZE: usize = 1024 * 4;
-// static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
+use embedded_alloc::LlffHeap;
+use portable_atomic::AtomicUsize;
 
-// pub fn init() {
-//     unsafe { HEAP.init(HEAP_MEM.as_ptr() as usize, HEAP_SIZE) }
-// }
+use iot_device::metrics::{self, HeapStats};
+
+use crate::log_error;
+
+include!(concat!(env!("OUT_DIR"), "/heap_size.rs"));
+
+#[global_allocator]
+static HEAP: Heap = Heap {
+    inner: LlffHeap::empty(),
+    peak: AtomicUsize::new(0),
+};
+
+#[link_section = ".heap"]
+static mut HEAP_MEMORY: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
+
+/// Wraps the heap to keep track of its usage.
+struct Heap {
+    inner: LlffHeap,
+    /// The most bytes allocated at once.
+    peak: AtomicUsize,
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let pointer = self.inner.alloc(layout);
+        if pointer.is_null() {
+            metrics::HEAP_ALLOCATIONS_FAILED.increment();
+            log_error!(
+                "Failed to allocate {} bytes, {} of {HEAP_SIZE} bytes free",
+                layout.size(),
+                self.inner.free()
+            );
+        } else {
+            self.peak.fetch_max(self.inner.used(), Ordering::Relaxed);
+        }
+        pointer
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        self.inner.dealloc(pointer, layout);
+    }
+}
+
+/// Hand the heap its memory, this **must** be run before anything is allocated.
+pub fn init() {
+    // Safety: only called once at startup, and nothing else uses the heap's memory.
+    unsafe {
+        HEAP.inner
+            .init(addr_of_mut!(HEAP_MEMORY) as usize, HEAP_SIZE);
+    }
+}
+
+pub fn stats() -> HeapStats {
+    HeapStats {
+        used: HEAP.inner.used(),
+        free: HEAP.inner.free(),
+        peak: HEAP.peak.load(Ordering::Relaxed),
+        failed: metrics::HEAP_ALLOCATIONS_FAILED.get(),
+    }
+}
@@ -0,0 +1,323 @@
+//! Commands read from the serial console while connected to a network.
+
+use core::str::FromStr;
+
+use embassy_futures::select::{select, Either};
+use embassy_net::Ipv4Address;
+use embassy_time::Duration;
+use heapless::String;
+
+use crate::{
+    networking::{power::PowerProfile, Client, Connected},
+    ota, print, println,
+    serial::{
+        self,
+        log::{self, Level},
+        print::{self, DetachedPolicy, OverflowPolicy},
+        read_line,
+    },
+    system::{self, memory},
+};
+
+const DEFAULT_PING_COUNT: u16 = 4;
+
+const COMMANDS: &[(&str, &str)] = &[
+    ("help", "Show this message"),
+    ("ping <address|host> [count]", "Send ICMP echo requests"),
+    ("dns <host>", "Look up the IPv4 addresses of a host"),
+    ("update <url>", "Install a signed firmware image and reboot"),
+    (
+        "stdout [policy]",
+        "Show or set what happens when output overflows",
+    ),
+    (
+        "detached [policy]",
+        "Show or set what happens to output with no terminal attached",
+    ),
+    (
+        "log [level]",
+        "Show or set the least important level logged",
+    ),
+    (
+        "uplink [wifi|usb]",
+        "Show or set the link requests are sent over",
+    ),
+    ("link", "Show the signal samples taken since joining"),
+    ("mem", "Show how much memory has been used"),
+    (
+        "power [profile]",
+        "Show or set the radio's power profile until the config changes",
+    ),
+    ("reboot", "Restart the device"),
+    (
+        "bootloader",
+        "Restart into the USB mass storage bootloader for flashing",
+    ),
+];
+
+/// Read and run commands from the serial console, forever.
+pub async fn run(client: &Client<Connected>) -> ! {
+    loop {
+        serial::wait_serial_up().await;
+        print!("> ");
+        let mut line = String::<128>::new();
+        // A line is abandoned if the host disconnects part way through, and the prompt shown again once it's back.
+        match select(read_line(&mut line), serial::wait_serial_down()).await {
+            Either::First(_) => {}
+            Either::Second(()) => continue,
+        }
+
+        let mut args = line.split_whitespace();
+        match args.next() {
+            None => {}
+            Some("help") => help().await,
+            Some("ping") => match args.next() {
+                Some(target) => {
+                    let count = args.next().map(u16::from_str);
+                    match count.unwrap_or(Ok(DEFAULT_PING_COUNT)) {
+                        Ok(count) => ping(client, target, count).await,
+                        Err(_) => println!("Invalid count"),
+                    }
+                }
+                None => println!("Usage: ping <address|host> [count]"),
+            },
+            Some("dns") => match args.next() {
+                Some(name) => lookup(client, name).await,
+                None => println!("Usage: dns <host>"),
+            },
+            Some("update") => match args.next() {
+                Some(url) => update(client, url).await,
+                None => println!("Usage: update <url>"),
+            },
+            Some("stdout") => stdout(args.next()).await,
+            Some("detached") => detached(args.next()).await,
+            Some("log") => log(args.next()).await,
+            Some("uplink") => uplink(args.next()).await,
+            Some("link") => link(client).await,
+            Some("mem") => mem().await,
+            Some("power") => power(client, args.next()).await,
+            Some("reboot") => {
+                println!("Rebooting");
+                system::reboot().await;
+            }
+            Some("bootloader") => {
+                println!("Rebooting into the bootloader");
+                system::reboot_to_bootloader().await;
+            }
+            Some(command) => println!("Unknown command `{command}`, try `help`"),
+        }
+    }
+}
+
+async fn help() {
+    for (usage, description) in COMMANDS {
+        println!("{usage:<30}{description}");
+    }
+}
+
+async fn link(client: &Client<Connected>) {
+    let history = client.link_history();
+    if history.is_empty() {
+        println!("No samples taken yet");
+    }
+    for sample in history {
+        println!(
+            "{:>6}s {} dBm, channel {}, {}, up to {}.{} Mbps",
+            sample.uptime,
+            sample.rssi,
+            sample.channel,
+            sample.bssid,
+            sample.max_rate / 1000,
+            sample.max_rate % 1000 / 100
+        );
+    }
+}
+
+async fn mem() {
+    let stack = memory::stack();
+    println!("Stack: {} of {} bytes at most", stack.used, stack.size);
+    let arena = memory::task_arena();
+    println!("Task arena: {} of {} bytes", arena.used, arena.size);
+
+    #[cfg(feature = "alloc")]
+    {
+        let heap = crate::allocator::stats();
+        println!(
+            "Heap: {} bytes used, {} free, {} at most, {} failed allocations",
+            heap.used, heap.free, heap.peak, heap.failed
+        );
+    }
+}
+
+async fn power(client: &Client<Connected>, profile: Option<&str>) {
+    let Some(name) = profile else {
+        println!("Power profile: {}", client.power_profile().name());
+        return;
+    };
+
+    if let Some(profile) = PowerProfile::ALL
+        .into_iter()
+        .find(|profile| profile.name() == name)
+    {
+        client.set_power_profile(profile).await;
+    } else {
+        print!("Unknown profile `{name}`, expected one of:");
+        for profile in PowerProfile::ALL {
+            print!(" {}", profile.name());
+        }
+        println!();
+    }
+}
+
+async fn ping(client: &Client<Connected>, target: &str, count: u16) {
+    let Some(address) = resolve(client, target).await else {
+        return;
+    };
+
+    println!("PING {target} ({address})");
+    let stats = client.ping(address, count).await;
+    println!(
+        "{} transmitted, {} received, {}% loss",
+        stats.transmitted,
+        stats.received,
+        stats.loss()
+    );
+
+    if let (Some(min), Some(average), Some(max)) = (stats.min, stats.average(), stats.max) {
+        println!(
+            "rtt min/avg/max = {}/{}/{} ms",
+            Millis(min),
+            Millis(average),
+            Millis(max)
+        );
+    }
+}
+
+async fn lookup(client: &Client<Connected>, name: &str) {
+    match client.lookup(name).await {
+        Ok(addresses) if addresses.is_empty() => println!("No addresses found for `{name}`"),
+        Ok(addresses) => {
+            for address in addresses {
+                println!("{name} has address {address}");
+            }
+        }
+        Err(error) => println!("Failed to look up `{name}`: `{error:?}`"),
+    }
+}
+
+async fn update(client: &Client<Connected>, url: &str) {
+    println!("Downloading update from `{url}`");
+    match ota::update(client, url).await {
+        Ok(()) => {
+            println!("Update verified, rebooting");
+            system::reboot().await;
+        }
+        Err(error) => println!("Failed to update: `{error}`"),
+    }
+}
+
+async fn stdout(policy: Option<&str>) {
+    let Some(name) = policy else {
+        println!("Overflow policy: {}", print::overflow_policy().name());
+        return;
+    };
+
+    if let Some(policy) = OverflowPolicy::ALL
+        .into_iter()
+        .find(|policy| policy.name() == name)
+    {
+        print::set_overflow_policy(policy);
+    } else {
+        print!("Unknown policy `{name}`, expected one of:");
+        for policy in OverflowPolicy::ALL {
+            print!(" {}", policy.name());
+        }
+        println!();
+    }
+}
+
+async fn detached(policy: Option<&str>) {
+    let Some(name) = policy else {
+        println!("Detached policy: {}", print::detached_policy().name());
+        return;
+    };
+
+    if let Some(policy) = DetachedPolicy::ALL
+        .into_iter()
+        .find(|policy| policy.name() == name)
+    {
+        print::set_detached_policy(policy);
+    } else {
+        print!("Unknown policy `{name}`, expected one of:");
+        for policy in DetachedPolicy::ALL {
+            print!(" {}", policy.name());
+        }
+        println!();
+    }
+}
+
+async fn log(level: Option<&str>) {
+    let Some(name) = level else {
+        println!("Log level: {}", log::level().name());
+        return;
+    };
+
+    if let Some(level) = Level::ALL.into_iter().find(|level| level.name() == name) {
+        log::set_level(level);
+    } else {
+        print!("Unknown level `{name}`, expected one of:");
+        for level in Level::ALL {
+            print!(" {}", level.name());
+        }
+        println!();
+    }
+}
+
+#[cfg(feature = "usb-ethernet")]
+async fn uplink(link: Option<&str>) {
+    use crate::networking::usb_ethernet;
+
+    match link {
+        None if usb_ethernet::preferred() => println!("Uplink: usb"),
+        None => println!("Uplink: wifi"),
+        Some("wifi") => usb_ethernet::set_preferred(false),
+        Some("usb") => usb_ethernet::set_preferred(true),
+        Some(link) => println!("Unknown uplink `{link}`, expected one of: wifi usb"),
+    }
+}
+
+#[cfg(not(feature = "usb-ethernet"))]
+async fn uplink(_: Option<&str>) {
+    println!("USB ethernet isn't enabled in this build");
+}
+
+/// Parse `target` as an address, or look it up if it is a host name.
+async fn resolve(client: &Client<Connected>, target: &str) -> Option<Ipv4Address> {
+    if let Ok(address) = Ipv4Address::from_str(target) {
+        return Some(address);
+    }
+
+    match client.lookup(target).await {
+        Ok(addresses) => {
+            let address = addresses.first().copied();
+            if address.is_none() {
+                println!("No addresses found for `{target}`");
+            }
+            address
+        }
+        Err(error) => {
+            println!("Failed to look up `{target}`: `{error:?}`");
+            None
+        }
+    }
+}
+
+/// Displays a [`Duration`] in milliseconds with microsecond precision.
+struct Millis(Duration);
+
+impl core::fmt::Display for Millis {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let micros = self.0.as_micros();
+        write!(f, "{}.{:03}", micros / 1000, micros % 1000)
+    }
+}
@@ -0,0 +1,323 @@
+//! Parsing of incoming HTTP/1.1 requests, routing them and framing the responses.
+//!
+//! Kept free of any network or hardware types so it can be exercised on the host. The server answers the same way
+//! over Wi-Fi and the USB network adapter, so this covers both links.
+
+use core::fmt;
+
+use heapless::Vec;
+use reqwless::request::Method;
+use serde::Deserialize;
+use serde_json_core::de;
+use thiserror_no_std::Error;
+
+/// The maximum amount of headers that will be stored for a single request.
+const MAX_HEADERS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok = 200,
+    Accepted = 202,
+    BadRequest = 400,
+    NotFound = 404,
+    MethodNotAllowed = 405,
+    PayloadTooLarge = 413,
+    InternalServerError = 500,
+}
+
+impl Status {
+    pub const fn reason(self) -> &'static str {
+        match self {
+            Self::Ok => "OK",
+            Self::Accepted => "Accepted",
+            Self::BadRequest => "Bad Request",
+            Self::NotFound => "Not Found",
+            Self::MethodNotAllowed => "Method Not Allowed",
+            Self::PayloadTooLarge => "Payload Too Large",
+            Self::InternalServerError => "Internal Server Error",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Error)]
+pub enum ParseError {
+    #[error("The request has not been fully received")]
+    Incomplete,
+    #[error("Malformed request line")]
+    BadRequestLine,
+    #[error("Unsupported method")]
+    UnsupportedMethod,
+    #[error("Unsupported HTTP version")]
+    UnsupportedVersion,
+    #[error("Malformed header")]
+    BadHeader,
+    #[error("More than {MAX_HEADERS} headers were sent")]
+    TooManyHeaders,
+    #[error("Invalid `Content-Length` header")]
+    BadContentLength,
+    #[error("Request contains invalid utf8")]
+    InvalidUtf8,
+}
+
+pub struct Request<'a> {
+    pub method: Method,
+    /// The requested path, excluding any query string.
+    pub path: &'a str,
+    pub headers: Vec<(&'a str, &'a str), MAX_HEADERS>,
+    pub body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    /// Parse a request from `buffer`.
+    ///
+    /// Returns [`ParseError::Incomplete`] if the headers or the body announced by
+    /// `Content-Length` have not been fully received yet, in which case the caller should read more data and retry.
+    pub fn parse(buffer: &'a [u8]) -> Result<Self, ParseError> {
+        let header_end = find(buffer, b"\r\n\r\n").ok_or(ParseError::Incomplete)?;
+        let head =
+            core::str::from_utf8(&buffer[..header_end]).map_err(|_| ParseError::InvalidUtf8)?;
+        let mut lines = head.split("\r\n");
+
+        let mut request_line = lines.next().ok_or(ParseError::BadRequestLine)?.split(' ');
+        let (Some(method), Some(target), Some(version), None) = (
+            request_line.next(),
+            request_line.next(),
+            request_line.next(),
+            request_line.next(),
+        ) else {
+            return Err(ParseError::BadRequestLine);
+        };
+
+        let method = parse_method(method)?;
+        if !target.starts_with('/') {
+            return Err(ParseError::BadRequestLine);
+        }
+        if version != "HTTP/1.1" && version != "HTTP/1.0" {
+            return Err(ParseError::UnsupportedVersion);
+        }
+
+        let path = target.split_once('?').map_or(target, |(path, _)| path);
+
+        let mut headers = Vec::new();
+        for line in lines {
+            let (name, value) = line.split_once(':').ok_or(ParseError::BadHeader)?;
+            if name.is_empty() || name.contains(' ') {
+                return Err(ParseError::BadHeader);
+            }
+            headers
+                .push((name, value.trim()))
+                .map_err(|_| ParseError::TooManyHeaders)?;
+        }
+
+        let mut request = Self {
+            method,
+            path,
+            headers,
+            body: &[],
+        };
+
+        let body_start = header_end + 4;
+        let body_length = request.content_length()?;
+        // The length comes from the client, so it can't be trusted not to overflow.
+        let body_end = body_start
+            .checked_add(body_length)
+            .ok_or(ParseError::BadContentLength)?;
+        if buffer.len() < body_end {
+            return Err(ParseError::Incomplete);
+        }
+        request.body = &buffer[body_start..body_end];
+
+        Ok(request)
+    }
+
+    /// Get the value of the first header matching `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    }
+
+    /// The length of the body as announced by the `Content-Length` header, or 0 if it is missing.
+    pub fn content_length(&self) -> Result<usize, ParseError> {
+        self.header("Content-Length").map_or(Ok(0), |length| {
+            length.parse().map_err(|_| ParseError::BadContentLength)
+        })
+    }
+
+    /// Deserialize the request body as json.
+    pub fn json<T: Deserialize<'a>>(&self) -> Result<T, de::Error> {
+        Ok(serde_json_core::from_slice(self.body)?.0)
+    }
+}
+
+/// Find the route in `routes` matching the request's method and path, which `key` gives for each route.
+///
+/// Fails with [`Status::MethodNotAllowed`] if only the path matches, or [`Status::NotFound`] if nothing does.
+pub fn route<'r, R>(
+    routes: &'r [R],
+    request: &Request<'_>,
+    key: impl Fn(&R) -> (Method, &str),
+) -> Result<&'r R, Status> {
+    let mut matched_path = false;
+    routes
+        .iter()
+        .find(|route| {
+            let (method, path) = key(route);
+            let path_matches = path == request.path;
+            matched_path |= path_matches;
+            path_matches && method == request.method
+        })
+        .ok_or(if matched_path {
+            Status::MethodNotAllowed
+        } else {
+            Status::NotFound
+        })
+}
+
+/// Write the status line and headers of a response with a `content_length` byte body, the connection is closed
+/// after each response.
+pub fn write_head(
+    head: &mut impl fmt::Write,
+    status: Status,
+    content_type: &str,
+    content_length: usize,
+) -> fmt::Result {
+    write!(
+        head,
+        "HTTP/1.1 {} {}\r\nContent-Type: {content_type}\r\nContent-Length: {content_length}\r\nConnection: close\r\n\r\n",
+        status as u16,
+        status.reason(),
+    )
+}
+
+fn parse_method(method: &str) -> Result<Method, ParseError> {
+    match method {
+        "GET" => Ok(Method::GET),
+        "POST" => Ok(Method::POST),
+        "PUT" => Ok(Method::PUT),
+        "DELETE" => Ok(Method::DELETE),
+        "HEAD" => Ok(Method::HEAD),
+        _ => Err(ParseError::UnsupportedMethod),
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_body() {
+        let request =
+            Request::parse(b"POST /config?x=1 HTTP/1.1\r\nContent-Length: 4\r\n\r\n{}{}").unwrap();
+        assert_eq!(request.method, Method::POST);
+        assert_eq!(request.path, "/config");
+        assert_eq!(request.body, b"{}{}");
+    }
+
+    #[test]
+    fn missing_content_length_has_empty_body() {
+        let request = Request::parse(b"GET /status HTTP/1.1\r\nHost: pico\r\n\r\nextra").unwrap();
+        assert_eq!(request.header("host"), Some("pico"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn truncated_body_is_incomplete() {
+        let result = Request::parse(b"POST /config HTTP/1.1\r\nContent-Length: 10\r\n\r\n{}");
+        assert_eq!(result.err(), Some(ParseError::Incomplete));
+    }
+
+    #[test]
+    fn truncated_headers_are_incomplete() {
+        let result = Request::parse(b"GET /status HTTP/1.1\r\nHost: pi");
+        assert_eq!(result.err(), Some(ParseError::Incomplete));
+    }
+
+    #[test]
+    fn bad_content_length() {
+        for length in ["", "-1", "abc", "1.5"] {
+            let request = format!("POST /config HTTP/1.1\r\nContent-Length: {length}\r\n\r\n");
+            let result = Request::parse(request.as_bytes());
+            assert_eq!(
+                result.err(),
+                Some(ParseError::BadContentLength),
+                "{length:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn overflowing_content_length() {
+        let result = Request::parse(
+            b"POST /config HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n{}",
+        );
+        assert_eq!(result.err(), Some(ParseError::BadContentLength));
+
+        let result = Request::parse(
+            b"POST /config HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n{}",
+        );
+        assert_eq!(result.err(), Some(ParseError::BadContentLength));
+    }
+
+    #[test]
+    fn malformed_request_line() {
+        let result = Request::parse(b"GET\r\n\r\n");
+        assert_eq!(result.err(), Some(ParseError::BadRequestLine));
+        let result = Request::parse(b"GET /status HTTP/2\r\n\r\n");
+        assert_eq!(result.err(), Some(ParseError::UnsupportedVersion));
+        let result = Request::parse(b"PATCH /status HTTP/1.1\r\n\r\n");
+        assert_eq!(result.err(), Some(ParseError::UnsupportedMethod));
+    }
+
+    const ROUTES: &[(Method, &str)] = &[
+        (Method::GET, "/status"),
+        (Method::GET, "/config"),
+        (Method::POST, "/config"),
+    ];
+
+    fn route_for(request: &[u8]) -> Result<(Method, &'static str), Status> {
+        let request = Request::parse(request).unwrap();
+        route(ROUTES, &request, |route| *route).copied()
+    }
+
+    #[test]
+    fn routes_by_method_and_path() {
+        assert_eq!(
+            route_for(b"POST /config HTTP/1.1\r\n\r\n"),
+            Ok((Method::POST, "/config"))
+        );
+        assert_eq!(
+            route_for(b"GET /status?verbose HTTP/1.0\r\n\r\n"),
+            Ok((Method::GET, "/status"))
+        );
+    }
+
+    #[test]
+    fn unknown_routes() {
+        assert_eq!(
+            route_for(b"DELETE /config HTTP/1.1\r\n\r\n"),
+            Err(Status::MethodNotAllowed)
+        );
+        assert_eq!(
+            route_for(b"GET /missing HTTP/1.1\r\n\r\n"),
+            Err(Status::NotFound)
+        );
+    }
+
+    #[test]
+    fn response_head() {
+        let mut head = String::new();
+        write_head(&mut head, Status::Accepted, "application/json", 12).unwrap();
+        assert_eq!(
+            head,
+            "HTTP/1.1 202 Accepted\r\nContent-Type: application/json\r\nContent-Length: 12\r\nConnection: close\r\n\r\n"
+        );
+    }
+}
@@ -0,0 +1,10 @@
+//! The parts of the firmware which don't touch the hardware, kept in a library so they can be tested on the host
+//! with `cargo test-host`.
+
+#![cfg_attr(not(test), no_std)]
+#![allow(clippy::missing_errors_doc)]
+#![allow(clippy::must_use_candidate)]
+
+pub mod http;
+pub mod mdns;
+pub mod metrics;
@@ -3,81 +3,267 @@
 #![allow(clippy::future_not_send)]
 #![allow(clippy::large_futures)]
 
-// extern crate alloc;
+#[cfg(feature = "alloc")]
+extern crate alloc;
 
+#[cfg(all(feature = "pico-w", feature = "pico2-w"))]
+compile_error!("Only one of the `pico-w` and `pico2-w` features can be enabled");
+#[cfg(not(any(feature = "pico-w", feature = "pico2-w")))]
+compile_error!("One of the `pico-w` or `pico2-w` features must be enabled");
+
+#[cfg(feature = "alloc")]
 mod allocator;
+mod console;
 mod networking;
+mod ota;
 mod serial;
+mod storage;
+mod system;
 
 use defmt::unwrap;
-use embassy_executor::Spawner;
+use embassy_executor::{InterruptExecutor, Spawner};
+use embassy_futures::select::{select, select4, Either, Either4};
 use embassy_rp::config::Config;
+use embassy_rp::interrupt;
+use embassy_rp::interrupt::{InterruptExt, Priority};
+use embassy_time::{Duration, Timer};
 
-use networking::{network_config::NetworkConfig, Client};
+use defmt_rtt as _;
+use networking::{
+    lease, network_config::NetworkConfig, power::PowerProfile, server, Client, Disconnected,
+};
 use reqwless::request::Method;
 use serde::Deserialize;
 use serial::init_serial;
-use {defmt_rtt as _, panic_probe as _};
+use system::{
+    crash, sleep,
+    watchdog::{self, Task},
+};
+
+const CONFIG_UPDATE_DELAY: Duration = Duration::from_secs(1);
+
+const LINK_SAMPLE_INTERVAL: Duration = Duration::from_secs(30);
+/// Reconnect once this many samples in a row are weaker than [`WEAK_RSSI`] or miss the access point, in case a stronger
+/// one is about.
+const WEAK_SAMPLES: usize = 4;
+const WEAK_RSSI: i16 = -80;
+
+const FIRMWARE_ERROR_INTERVAL: Duration = Duration::from_secs(5);
+
+static SUPERVISOR_EXECUTOR: InterruptExecutor = InterruptExecutor::new();
+
+#[interrupt]
+unsafe fn SWI_IRQ_1() {
+    SUPERVISOR_EXECUTOR.on_interrupt();
+}
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
-    // allocator::init();
+    #[cfg(feature = "alloc")]
+    allocator::init();
 
     let peripherals = embassy_rp::init(Config::default());
 
+    crash::init().await;
+    lease::init().await;
+    storage::init(peripherals.FLASH).await;
+    system::init(peripherals.WATCHDOG).await;
+    #[cfg(feature = "pico-w")]
+    sleep::init(peripherals.RTC).await;
+
+    // The watchdog supervisor runs at a higher priority, so it can still notice tasks hogging the main executor.
+    interrupt::SWI_IRQ_1.set_priority(Priority::P2);
+    let supervisor = SUPERVISOR_EXECUTOR.start(interrupt::SWI_IRQ_1);
+    unwrap!(supervisor.spawn(watchdog::supervise()));
+
+    unwrap!(spawner.spawn(ota::watch_trial()));
+
     unwrap!(spawner.spawn(init_serial(peripherals.USB)));
 
-    serial::wait_serial_up().await;
+    // Everything else runs on the main task, which checks in with the watchdog each time round its loops.
+    let app = async {
+        let saved_config = NetworkConfig::load().await;
+        // Duty cycled devices usually run without a host attached, so don't wait for one.
+        if !saved_config
+            .as_ref()
+            .is_some_and(|config| config.power.is_duty_cycled())
+        {
+            watchdog::idle(Task::Main, serial::wait_serial_up()).await;
+        }
+        watchdog::check_in(Task::Main);
 
-    let mut disconnected_client = Client::new(
-        &spawner,
-        peripherals.PIN_23,
-        peripherals.PIN_24,
-        peripherals.PIN_25,
-        peripherals.PIN_29,
-        peripherals.PIO0,
-        peripherals.DMA_CH0,
-    )
-    .await;
+        if let Some(missed) = watchdog::missed_before_reset() {
+            println!("Reset by the watchdog after tasks stopped responding: {missed}");
+        }
+        if let Some(report) = crash::previous().await {
+            println!("Crashed before the last reset: {report}");
+        }
 
-    let client = loop {
-        let network_config = NetworkConfig::generate().await;
+        let mut disconnected_client = match Client::new(
+            &spawner,
+            peripherals.PIN_23,
+            peripherals.PIN_24,
+            peripherals.PIN_25,
+            peripherals.PIN_29,
+            peripherals.PIO0,
+            peripherals.DMA_CH0,
+        )
+        .await
+        {
+            Ok(client) => client,
+            Err(error) => loop {
+                watchdog::check_in(Task::Main);
+                println!("Failed to start the wireless chip: `{error}`");
+                Timer::after(FIRMWARE_ERROR_INTERVAL).await;
+            },
+        };
 
-        println!("Attempting to connect to `{}`", network_config.ssid.trim());
+        // Only configs which aren't in flash yet are saved, so reconnecting doesn't wear it.
+        let mut unsaved = saved_config.is_none();
+        let mut network_config = match saved_config {
+            Some(network_config) => {
+                println!("Using saved config for `{}`", network_config.ssid);
+                network_config
+            }
+            None => watchdog::idle(Task::Main, NetworkConfig::generate()).await,
+        };
+        disconnected_client
+            .set_power_profile(network_config.power)
+            .await;
+
+        loop {
+            let client = loop {
+                watchdog::check_in(Task::Main);
+                println!("Attempting to connect to `{}`", network_config.ssid.trim());
+
+                match disconnected_client.connect(&network_config).await {
+                    Ok(client) => {
+                        println!("Connected to `{}`", network_config.ssid.trim());
+                        if unsaved {
+                            match network_config.save().await {
+                                Ok(()) => unsaved = false,
+                                Err(error) => println!("Failed to save network config: `{error}`"),
+                            }
+                        }
+                        if let Err(error) = ota::confirm().await {
+                            println!("Failed to confirm update: `{error}`");
+                        }
+                        break client;
+                    }
+                    Err((error, client)) => {
+                        disconnected_client = client;
+                        println!("Failed to connect to network: `{error}`");
+                        // Nobody is around to enter a new config, so try again at the next upload.
+                        if disconnected_client.power_profile().is_duty_cycled() {
+                            wait_for_upload(&disconnected_client, network_config.upload_interval)
+                                .await;
+                            continue;
+                        }
+                        network_config =
+                            watchdog::idle(Task::Main, NetworkConfig::generate()).await;
+                        unsaved = true;
+                        disconnected_client
+                            .set_power_profile(network_config.power)
+                            .await;
+                    }
+                };
+            };
 
-        match disconnected_client.connect(&network_config).await {
-            Ok(client) => {
-                println!("Connected to `{}`", network_config.ssid.trim());
-                break client;
+            client.print_config().await;
+            crash::upload(&client).await;
+
+            let mut buffer = Client::BLANK_REQUEST_BUFFER;
+            match client
+                .request_with_data::<ApiResponse>(
+                    "http://worldtimeapi.org/api/timezone/Europe/Berlin",
+                    Method::GET,
+                    None,
+                    None,
+                    &mut buffer,
+                )
+                .await
+            {
+                Ok((_, data)) => println!("{}", data.datetime),
+                Err(err) => println!("{err}"),
             }
-            Err((error, client)) => {
-                disconnected_client = client;
-                println!("Failed to connect to network: `{error}`");
+
+            if client.power_profile().is_duty_cycled() {
+                disconnected_client = client.disconnect().await;
+                wait_for_upload(&disconnected_client, network_config.upload_interval).await;
+                continue;
             }
-        };
-    };
 
-    client.print_config().await;
+            let update = async {
+                let config = NetworkConfig::wait_for_update().await;
+                // Give the server a chance to respond before the connection is dropped.
+                Timer::after(CONFIG_UPDATE_DELAY).await;
+                config
+            };
 
-    let mut buffer = Client::BLANK_REQUEST_BUFFER;
-    let (_, data) = match client
-        .request_with_data::<ApiResponse>(
-            "http://worldtimeapi.org/api/timezone/Europe/Berlin",
-            Method::GET,
-            None,
-            None,
-            &mut buffer,
-        )
-        .await
-    {
-        Ok(body) => body,
-        Err(err) => {
-            println!("{err}");
-            return;
+            let link_monitor = async {
+                loop {
+                    watchdog::check_in(Task::Main);
+                    // Switching to a duty cycled profile at runtime takes effect on the next sample.
+                    if client.power_profile().is_duty_cycled() {
+                        break;
+                    }
+                    client.sample_link().await;
+                    if client.link_below(WEAK_RSSI, WEAK_SAMPLES) {
+                        break;
+                    }
+                    Timer::after(LINK_SAMPLE_INTERVAL).await;
+                }
+            };
+
+            match select4(
+                client.serve(server::ROUTES),
+                client.advertise(),
+                update,
+                select(link_monitor, console::run(&client)),
+            )
+            .await
+            {
+                Either4::Third(config) => {
+                    println!("Network config updated, reconnecting");
+                    network_config = config;
+                    unsaved = true;
+                    disconnected_client = client.disconnect().await;
+                    disconnected_client
+                        .set_power_profile(network_config.power)
+                        .await;
+                }
+                Either4::Fourth(Either::First(())) if client.power_profile().is_duty_cycled() => {
+                    disconnected_client = client.disconnect().await;
+                    wait_for_upload(&disconnected_client, network_config.upload_interval).await;
+                }
+                Either4::Fourth(Either::First(())) => {
+                    println!("Signal stayed below {WEAK_RSSI} dBm or was lost, reconnecting");
+                    disconnected_client = client.disconnect().await;
+                }
+                Either4::First(never)
+                | Either4::Second(never)
+                | Either4::Fourth(Either::Second(never)) => match never {},
+            }
         }
     };
 
-    println!("{}", data.datetime);
+    app.await;
+}
+
+/// Wait off the network until the next round of uploads, which doesn't return with [`PowerProfile::DeepSleep`].
+///
+/// Otherwise the radio stays powered but idle, as powering it down would mean loading its firmware again on waking.
+async fn wait_for_upload(client: &Client<Disconnected>, interval: Duration) {
+    if client.power_profile() == PowerProfile::DeepSleep {
+        lease::suspend(interval).await;
+        sleep::deep_sleep(interval).await;
+    }
+
+    println!(
+        "Battery saver, leaving the network for {}s",
+        interval.as_secs()
+    );
+    watchdog::idle(Task::Main, Timer::after(interval)).await;
 }
 
 #[derive(Deserialize, Default)]
@@ -0,0 +1,553 @@
+//! A minimal mDNS responder (RFC 6762) advertising the device's http server through DNS-SD (RFC 6763).
+//!
+//! Only builds the packets, the socket is handled by the firmware.
+
+use core::net::Ipv4Addr;
+
+use heapless::{String, Vec};
+use thiserror_no_std::Error;
+
+pub const MDNS_PORT: u16 = 5353;
+
+const MAX_NAME_LEN: usize = 128;
+
+const SERVICE_NAME: &str = "_http._tcp.local";
+const SERVICES_META_NAME: &str = "_services._dns-sd._udp.local";
+
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+/// The most legacy resolvers may cache records for, RFC 6762 section 6.7.
+const LEGACY_TTL: u32 = 10;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+/// Set on the class of unique records to tell receivers to replace any cached records.
+const CLASS_CACHE_FLUSH: u16 = 0x8000;
+/// Set on the class of a question when the querier wants a unicast response.
+const CLASS_UNICAST_RESPONSE: u16 = 0x8000;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+#[derive(Clone, Copy, Debug, Error)]
+pub enum MdnsError {
+    #[error("Packet is truncated")]
+    Truncated,
+    #[error("Name is malformed or too long")]
+    BadName,
+    #[error("Response does not fit in the buffer")]
+    ResponseOverflow,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Record {
+    /// `<hostname>.local` to the device's address.
+    Address,
+    /// `_http._tcp.local` to the service instance.
+    ServicePointer,
+    /// `_services._dns-sd._udp.local` to `_http._tcp.local`.
+    ServicesPointer,
+    /// The host and port of the service instance.
+    Service,
+    /// The metadata of the service instance.
+    Text,
+}
+
+/// Answers queries for `hostname` and its http service.
+pub struct Responder<'a> {
+    hostname: &'a str,
+    address: Ipv4Addr,
+    /// The port the http service is on.
+    port: u16,
+}
+
+/// How a response to a query should be sent.
+pub enum Destination {
+    Multicast,
+    Unicast,
+}
+
+impl<'a> Responder<'a> {
+    pub const fn new(hostname: &'a str, address: Ipv4Addr, port: u16) -> Self {
+        Self {
+            hostname,
+            address,
+            port,
+        }
+    }
+
+    /// Build a response to `query`, received from `source_port`, in `buffer`.
+    ///
+    /// Returns [`None`] if the packet is not a query or asks about nothing this device owns.
+    pub fn respond(
+        &self,
+        query: &[u8],
+        source_port: u16,
+        buffer: &mut [u8],
+    ) -> Result<Option<(usize, Destination)>, MdnsError> {
+        let header = query.get(..12).ok_or(MdnsError::Truncated)?;
+        let id = u16::from_be_bytes([header[0], header[1]]);
+        let flags = u16::from_be_bytes([header[2], header[3]]);
+        let questions = u16::from_be_bytes([header[4], header[5]]);
+
+        if flags & FLAG_RESPONSE != 0 {
+            return Ok(None);
+        }
+
+        let mut answers = Vec::<Record, 5>::new();
+        let mut unicast = false;
+        let mut offset = 12;
+
+        for _ in 0..questions {
+            let mut name = String::<MAX_NAME_LEN>::new();
+            offset = read_name(query, offset, &mut name)?;
+            let fields = query.get(offset..offset + 4).ok_or(MdnsError::Truncated)?;
+            let kind = u16::from_be_bytes([fields[0], fields[1]]);
+            let class = u16::from_be_bytes([fields[2], fields[3]]);
+            offset += 4;
+
+            unicast |= class & CLASS_UNICAST_RESPONSE != 0;
+
+            for record in self.matching_records(&name, kind) {
+                if !answers.contains(&record) {
+                    answers.push(record).ok();
+                }
+            }
+        }
+
+        if answers.is_empty() {
+            return Ok(None);
+        }
+
+        // Queries not sent from the mDNS port come from simple resolvers, which expect a unicast
+        // response carrying their id and questions, like a regular DNS server would send.
+        let legacy = source_port != MDNS_PORT;
+        let length = if legacy {
+            let questions = Questions {
+                count: questions,
+                bytes: &query[12..offset],
+            };
+            self.write_response(id, Some(questions), &answers, buffer)?
+        } else {
+            self.write_response(0, None, &answers, buffer)?
+        };
+        let destination = if unicast || legacy {
+            Destination::Unicast
+        } else {
+            Destination::Multicast
+        };
+
+        Ok(Some((length, destination)))
+    }
+
+    /// Build an unsolicited response announcing every record, sent when the responder starts.
+    pub fn announcement(&self, buffer: &mut [u8]) -> Result<usize, MdnsError> {
+        self.write_response(
+            0,
+            None,
+            &[
+                Record::Address,
+                Record::ServicePointer,
+                Record::Service,
+                Record::Text,
+            ],
+            buffer,
+        )
+    }
+
+    fn matching_records(&self, name: &str, kind: u16) -> Vec<Record, 3> {
+        let mut records = Vec::new();
+        let is = |record_type| kind == record_type || kind == TYPE_ANY;
+
+        if self.is_host_name(name) && is(TYPE_A) {
+            records.push(Record::Address).ok();
+        } else if name.eq_ignore_ascii_case(SERVICE_NAME) && is(TYPE_PTR) {
+            records.push(Record::ServicePointer).ok();
+        } else if name.eq_ignore_ascii_case(SERVICES_META_NAME) && is(TYPE_PTR) {
+            records.push(Record::ServicesPointer).ok();
+        } else if self.is_instance_name(name) {
+            if is(TYPE_SRV) {
+                records.push(Record::Service).ok();
+            }
+            if is(TYPE_TXT) {
+                records.push(Record::Text).ok();
+            }
+        }
+
+        records
+    }
+
+    fn is_host_name(&self, name: &str) -> bool {
+        self.is_name_under(name, "local")
+    }
+
+    fn is_instance_name(&self, name: &str) -> bool {
+        self.is_name_under(name, SERVICE_NAME)
+    }
+
+    /// Whether `name` is `<hostname>.<domain>`, ignoring case.
+    fn is_name_under(&self, name: &str, domain: &str) -> bool {
+        let (name, hostname) = (name.as_bytes(), self.hostname.as_bytes());
+        name.len() == hostname.len() + 1 + domain.len()
+            && name[..hostname.len()].eq_ignore_ascii_case(hostname)
+            && name[hostname.len()] == b'.'
+            && name[hostname.len() + 1..].eq_ignore_ascii_case(domain.as_bytes())
+    }
+
+    /// Write a response with `answers`, repeating `questions` for legacy queries.
+    fn write_response(
+        &self,
+        id: u16,
+        questions: Option<Questions>,
+        answers: &[Record],
+        buffer: &mut [u8],
+    ) -> Result<usize, MdnsError> {
+        // Records a resolver will need next are included up front to save it another query.
+        let mut additional = Vec::<Record, 3>::new();
+        if answers.contains(&Record::ServicePointer) {
+            for record in [Record::Service, Record::Text, Record::Address] {
+                if !answers.contains(&record) {
+                    additional.push(record).ok();
+                }
+            }
+        } else if answers.contains(&Record::Service) && !answers.contains(&Record::Address) {
+            additional.push(Record::Address).ok();
+        }
+
+        let mut writer = Writer::new(buffer);
+        writer.u16(id)?;
+        writer.u16(FLAG_RESPONSE | FLAG_AUTHORITATIVE)?;
+        writer.u16(questions.map_or(0, |questions| questions.count))?;
+        writer.u16(count(answers.len()))?;
+        writer.u16(0)?;
+        writer.u16(count(additional.len()))?;
+
+        // The questions start straight after the header as they did in the query, so any compressed names in them
+        // still point to the right place.
+        if let Some(questions) = questions {
+            writer.bytes(questions.bytes)?;
+        }
+
+        let legacy = questions.is_some();
+        for record in answers.iter().chain(additional.iter()) {
+            self.write_record(&mut writer, *record, legacy)?;
+        }
+
+        Ok(writer.position)
+    }
+
+    /// Write `record`, without the cache flush bit and with a short TTL for legacy resolvers, which know nothing of
+    /// either.
+    fn write_record(
+        &self,
+        writer: &mut Writer,
+        record: Record,
+        legacy: bool,
+    ) -> Result<(), MdnsError> {
+        let header = |writer: &mut Writer, kind, unique: bool, ttl: u32| {
+            if legacy {
+                writer.record_header(kind, CLASS_IN, ttl.min(LEGACY_TTL))
+            } else if unique {
+                writer.record_header(kind, CLASS_IN | CLASS_CACHE_FLUSH, ttl)
+            } else {
+                writer.record_header(kind, CLASS_IN, ttl)
+            }
+        };
+
+        match record {
+            Record::Address => {
+                writer.labels(&[self.hostname, "local"])?;
+                header(writer, TYPE_A, true, HOST_TTL)?;
+                writer.rdata(|writer| writer.bytes(&self.address.octets()))
+            }
+            Record::ServicePointer => {
+                writer.name(SERVICE_NAME)?;
+                header(writer, TYPE_PTR, false, SERVICE_TTL)?;
+                writer.rdata(|writer| self.write_instance_name(writer))
+            }
+            Record::ServicesPointer => {
+                writer.name(SERVICES_META_NAME)?;
+                header(writer, TYPE_PTR, false, SERVICE_TTL)?;
+                writer.rdata(|writer| writer.name(SERVICE_NAME))
+            }
+            Record::Service => {
+                self.write_instance_name(writer)?;
+                header(writer, TYPE_SRV, true, HOST_TTL)?;
+                writer.rdata(|writer| {
+                    // Priority and weight.
+                    writer.u16(0)?;
+                    writer.u16(0)?;
+                    writer.u16(self.port)?;
+                    writer.labels(&[self.hostname, "local"])
+                })
+            }
+            Record::Text => {
+                self.write_instance_name(writer)?;
+                header(writer, TYPE_TXT, true, SERVICE_TTL)?;
+                writer.rdata(|writer| {
+                    const PATH: &[u8] = b"path=/status";
+                    writer.bytes(&[count(PATH.len()).to_be_bytes()[1]])?;
+                    writer.bytes(PATH)
+                })
+            }
+        }
+    }
+
+    fn write_instance_name(&self, writer: &mut Writer) -> Result<(), MdnsError> {
+        writer.labels(&[self.hostname, "_http", "_tcp", "local"])
+    }
+}
+
+/// The question section of a legacy query, repeated in the response.
+#[derive(Clone, Copy)]
+struct Questions<'a> {
+    count: u16,
+    bytes: &'a [u8],
+}
+
+/// Read a possibly compressed name starting at `offset` into `name` as dot separated labels.
+///
+/// Returns the offset following the name.
+fn read_name(
+    packet: &[u8],
+    mut offset: usize,
+    name: &mut String<MAX_NAME_LEN>,
+) -> Result<usize, MdnsError> {
+    let mut end = None;
+    // Bound the amount of pointers followed so malicious packets can't loop forever.
+    for _ in 0..16 {
+        let length = *packet.get(offset).ok_or(MdnsError::Truncated)?;
+        match length {
+            0 => return Ok(end.unwrap_or(offset + 1)),
+            0xc0.. => {
+                let low = *packet.get(offset + 1).ok_or(MdnsError::Truncated)?;
+                end.get_or_insert(offset + 2);
+                offset = usize::from(u16::from_be_bytes([length & 0x3f, low]));
+            }
+            1..=63 => {
+                let label = packet
+                    .get(offset + 1..offset + 1 + usize::from(length))
+                    .ok_or(MdnsError::Truncated)?;
+                let label = core::str::from_utf8(label).map_err(|_| MdnsError::BadName)?;
+                if !name.is_empty() {
+                    name.push('.').map_err(|()| MdnsError::BadName)?;
+                }
+                name.push_str(label).map_err(|()| MdnsError::BadName)?;
+                offset += 1 + usize::from(length);
+            }
+            _ => return Err(MdnsError::BadName),
+        }
+    }
+    Err(MdnsError::BadName)
+}
+
+fn count(length: usize) -> u16 {
+    u16::try_from(length).unwrap_or(u16::MAX)
+}
+
+struct Writer<'a> {
+    buffer: &'a mut [u8],
+    position: usize,
+}
+
+impl<'a> Writer<'a> {
+    const fn new(buffer: &'a mut [u8]) -> Self {
+        Self {
+            buffer,
+            position: 0,
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), MdnsError> {
+        let end = self.position + bytes.len();
+        self.buffer
+            .get_mut(self.position..end)
+            .ok_or(MdnsError::ResponseOverflow)?
+            .copy_from_slice(bytes);
+        self.position = end;
+        Ok(())
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), MdnsError> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> Result<(), MdnsError> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    /// Write a dot separated name, uncompressed.
+    fn name(&mut self, name: &str) -> Result<(), MdnsError> {
+        let mut labels = Vec::<&str, 8>::new();
+        for label in name.split('.') {
+            labels.push(label).map_err(|_| MdnsError::BadName)?;
+        }
+        self.labels(&labels)
+    }
+
+    fn labels(&mut self, labels: &[&str]) -> Result<(), MdnsError> {
+        for label in labels {
+            let length = u8::try_from(label.len())
+                .ok()
+                .filter(|length| (1..=63).contains(length))
+                .ok_or(MdnsError::BadName)?;
+            self.bytes(&[length])?;
+            self.bytes(label.as_bytes())?;
+        }
+        self.bytes(&[0])
+    }
+
+    fn record_header(&mut self, kind: u16, class: u16, ttl: u32) -> Result<(), MdnsError> {
+        self.u16(kind)?;
+        self.u16(class)?;
+        self.u32(ttl)
+    }
+
+    /// Write record data prefixed with its length.
+    fn rdata(
+        &mut self,
+        write: impl FnOnce(&mut Self) -> Result<(), MdnsError>,
+    ) -> Result<(), MdnsError> {
+        let length_position = self.position;
+        self.u16(0)?;
+        write(self)?;
+        let length = count(self.position - length_position - 2);
+        self.buffer[length_position..length_position + 2].copy_from_slice(&length.to_be_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOSTNAME: &str = "pico-a1b2c3";
+    const ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 42);
+    const QUERY_ID: u16 = 0x1234;
+
+    fn query(name: &str, kind: u16, class: u16) -> std::vec::Vec<u8> {
+        let mut packet = std::vec::Vec::new();
+        packet.extend_from_slice(&QUERY_ID.to_be_bytes());
+        // Flags, one question and no records.
+        packet.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            packet.push(u8::try_from(label.len()).unwrap());
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend_from_slice(&kind.to_be_bytes());
+        packet.extend_from_slice(&class.to_be_bytes());
+        packet
+    }
+
+    fn respond(query: &[u8], source_port: u16) -> Option<(std::vec::Vec<u8>, Destination)> {
+        let responder = Responder::new(HOSTNAME, ADDRESS, 80);
+        let mut buffer = [0; 512];
+        responder
+            .respond(query, source_port, &mut buffer)
+            .unwrap()
+            .map(|(length, destination)| (buffer[..length].to_vec(), destination))
+    }
+
+    fn field(packet: &[u8], offset: usize) -> u16 {
+        u16::from_be_bytes([packet[offset], packet[offset + 1]])
+    }
+
+    /// The type, class, TTL and data of the record at `offset`, which has an uncompressed name.
+    fn record(packet: &[u8], mut offset: usize) -> (u16, u16, u32, &[u8]) {
+        while packet[offset] != 0 {
+            offset += 1 + usize::from(packet[offset]);
+        }
+        offset += 1;
+        let ttl = u32::from_be_bytes(packet[offset + 4..offset + 8].try_into().unwrap());
+        let length = usize::from(field(packet, offset + 8));
+        (
+            field(packet, offset),
+            field(packet, offset + 2),
+            ttl,
+            &packet[offset + 10..offset + 10 + length],
+        )
+    }
+
+    #[test]
+    fn multicast_query() {
+        let (response, destination) =
+            respond(&query("pico-a1b2c3.local", TYPE_A, CLASS_IN), MDNS_PORT).unwrap();
+        assert!(matches!(destination, Destination::Multicast));
+        assert_eq!(field(&response, 0), 0);
+        assert_eq!(field(&response, 4), 0);
+        assert_eq!(field(&response, 6), 1);
+        assert_eq!(
+            record(&response, 12),
+            (
+                TYPE_A,
+                CLASS_IN | CLASS_CACHE_FLUSH,
+                HOST_TTL,
+                &ADDRESS.octets()[..]
+            )
+        );
+    }
+
+    #[test]
+    fn unicast_response_query() {
+        let query = query(
+            "PICO-A1B2C3.local",
+            TYPE_A,
+            CLASS_IN | CLASS_UNICAST_RESPONSE,
+        );
+        let (response, destination) = respond(&query, MDNS_PORT).unwrap();
+        assert!(matches!(destination, Destination::Unicast));
+        assert_eq!(field(&response, 0), 0);
+        assert_eq!(field(&response, 4), 0);
+        assert_eq!(record(&response, 12).1, CLASS_IN | CLASS_CACHE_FLUSH);
+    }
+
+    #[test]
+    fn legacy_query() {
+        let query = query("_http._tcp.local", TYPE_PTR, CLASS_IN);
+        let (response, destination) = respond(&query, 40000).unwrap();
+        assert!(matches!(destination, Destination::Unicast));
+        assert_eq!(field(&response, 0), QUERY_ID);
+        assert_eq!(field(&response, 4), 1);
+        assert_eq!(&response[12..query.len()], &query[12..]);
+
+        // The pointer, then the service, text and address records as additional records.
+        assert_eq!(field(&response, 6), 1);
+        assert_eq!(field(&response, 10), 3);
+        let mut offset = query.len();
+        for _ in 0..4 {
+            let (_, class, ttl, data) = record(&response, offset);
+            assert_eq!(class, CLASS_IN);
+            assert!(ttl <= LEGACY_TTL);
+            let name_length = response[offset..]
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap()
+                + 1;
+            offset += name_length + 10 + data.len();
+        }
+        assert_eq!(offset, response.len());
+    }
+
+    #[test]
+    fn ignores_other_names() {
+        assert!(respond(&query("other.local", TYPE_A, CLASS_IN), MDNS_PORT).is_none());
+        assert!(respond(&query("pico-a1b2c3.local", TYPE_TXT, CLASS_IN), MDNS_PORT).is_none());
+    }
+
+    #[test]
+    fn truncated_query() {
+        let query = query("pico-a1b2c3.local", TYPE_A, CLASS_IN);
+        let responder = Responder::new(HOSTNAME, ADDRESS, 80);
+        let mut buffer = [0; 512];
+        assert!(matches!(
+            responder.respond(&query[..query.len() - 2], MDNS_PORT, &mut buffer),
+            Err(MdnsError::Truncated)
+        ));
+    }
+}
@@ -0,0 +1,255 @@
+//! Counters and gauges kept by the firmware, served in the Prometheus text exposition format.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::Ordering;
+
+use portable_atomic::{AtomicI32, AtomicU32};
+use serde::Serialize;
+
+/// The most [`render`] writes, with every metric at its longest value on the 32-bit target.
+pub const MAX_RENDER_SIZE: usize = 3072;
+
+pub static REQUESTS_SENT: Counter = Counter::new();
+pub static REQUESTS_FAILED_NETWORK: Counter = Counter::new();
+pub static REQUESTS_FAILED_HTTP_CODE: Counter = Counter::new();
+pub static REQUESTS_FAILED_JSON_DECODING: Counter = Counter::new();
+pub static REQUESTS_FAILED_UTF_DECODING: Counter = Counter::new();
+
+pub static CONNECTIONS: Counter = Counter::new();
+pub static RECONNECTS: Counter = Counter::new();
+
+/// How long the last DHCP configuration took to be acquired.
+pub static DHCP_TIME_MS: Gauge = Gauge::new();
+pub static RSSI: Gauge = Gauge::new();
+
+pub static STDIN_HIGH_WATER: Gauge = Gauge::new();
+pub static STDOUT_HIGH_WATER: Gauge = Gauge::new();
+/// Bytes of stdout discarded by the overflow policy.
+pub static STDOUT_DROPPED: Counter = Counter::new();
+/// Log lines discarded because the log buffer was full.
+pub static LOG_DROPPED: Counter = Counter::new();
+/// Allocations which didn't fit in the heap.
+#[cfg(feature = "alloc")]
+pub static HEAP_ALLOCATIONS_FAILED: Counter = Counter::new();
+
+pub struct Counter(AtomicU32);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU32::new(0))
+    }
+
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u32) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that can go up and down, which is omitted from the output until it is first set.
+pub struct Gauge(AtomicI32);
+
+impl Gauge {
+    const UNSET: i32 = i32::MIN;
+
+    const fn new() -> Self {
+        Self(AtomicI32::new(Self::UNSET))
+    }
+
+    pub fn set(&self, value: i32) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    /// Set the gauge to `value` if it is higher than the current value, for tracking high-water marks.
+    pub fn set_max(&self, value: i32) {
+        self.0.fetch_max(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<i32> {
+        Some(self.0.load(Ordering::Relaxed)).filter(|value| *value != Self::UNSET)
+    }
+}
+
+/// How much of the heap is in use.
+#[derive(Serialize)]
+pub struct HeapStats {
+    pub used: usize,
+    pub free: usize,
+    pub peak: usize,
+    /// Allocations which didn't fit.
+    pub failed: u32,
+}
+
+/// Write every metric in the Prometheus text exposition format, the heap's only when there is one.
+pub fn render(w: &mut impl Write, uptime_secs: u64, heap: Option<&HeapStats>) -> fmt::Result {
+    write_counter(
+        w,
+        "iot_requests_sent_total",
+        "HTTP requests sent.",
+        &REQUESTS_SENT,
+    )?;
+
+    write_header(
+        w,
+        "iot_requests_failed_total",
+        "HTTP requests that failed, by error kind.",
+        "counter",
+    )?;
+    for (kind, counter) in [
+        ("network", &REQUESTS_FAILED_NETWORK),
+        ("http_code", &REQUESTS_FAILED_HTTP_CODE),
+        ("json_decoding", &REQUESTS_FAILED_JSON_DECODING),
+        ("utf_decoding", &REQUESTS_FAILED_UTF_DECODING),
+    ] {
+        writeln!(
+            w,
+            "iot_requests_failed_total{{kind=\"{kind}\"}} {}",
+            counter.get()
+        )?;
+    }
+
+    write_counter(
+        w,
+        "iot_wifi_connections_total",
+        "Successful connections to a network.",
+        &CONNECTIONS,
+    )?;
+    write_counter(
+        w,
+        "iot_wifi_reconnects_total",
+        "Successful connections after the first.",
+        &RECONNECTS,
+    )?;
+
+    write_gauge(
+        w,
+        "iot_dhcp_duration_milliseconds",
+        "Time taken to acquire the last DHCP configuration.",
+        &DHCP_TIME_MS,
+    )?;
+    write_gauge(
+        w,
+        "iot_wifi_rssi_dbm",
+        "Signal strength of the joined network.",
+        &RSSI,
+    )?;
+    write_gauge(
+        w,
+        "iot_stdin_high_water_bytes",
+        "Most bytes held in the stdin buffer at once.",
+        &STDIN_HIGH_WATER,
+    )?;
+    write_gauge(
+        w,
+        "iot_stdout_high_water_bytes",
+        "Most bytes held in the stdout buffer at once.",
+        &STDOUT_HIGH_WATER,
+    )?;
+    write_counter(
+        w,
+        "iot_stdout_dropped_bytes_total",
+        "Bytes of stdout discarded because the buffer was full.",
+        &STDOUT_DROPPED,
+    )?;
+    write_counter(
+        w,
+        "iot_log_dropped_lines_total",
+        "Log lines discarded because the log buffer was full.",
+        &LOG_DROPPED,
+    )?;
+
+    if let Some(heap) = heap {
+        write_heap(w, heap)?;
+    }
+
+    write_header(w, "iot_uptime_seconds", "Time since boot.", "gauge")?;
+    writeln!(w, "iot_uptime_seconds {uptime_secs}")
+}
+
+fn write_heap(w: &mut impl Write, heap: &HeapStats) -> fmt::Result {
+    for (name, help, value) in [
+        (
+            "iot_heap_used_bytes",
+            "Bytes allocated on the heap.",
+            heap.used,
+        ),
+        ("iot_heap_free_bytes", "Bytes free on the heap.", heap.free),
+        (
+            "iot_heap_peak_bytes",
+            "Most bytes allocated on the heap at once.",
+            heap.peak,
+        ),
+    ] {
+        write_header(w, name, help, "gauge")?;
+        writeln!(w, "{name} {value}")?;
+    }
+    write_header(
+        w,
+        "iot_heap_allocations_failed_total",
+        "Allocations which didn't fit in the heap.",
+        "counter",
+    )?;
+    writeln!(w, "iot_heap_allocations_failed_total {}", heap.failed)
+}
+
+fn write_header(w: &mut impl Write, name: &str, help: &str, kind: &str) -> fmt::Result {
+    writeln!(w, "# HELP {name} {help}")?;
+    writeln!(w, "# TYPE {name} {kind}")
+}
+
+fn write_counter(w: &mut impl Write, name: &str, help: &str, counter: &Counter) -> fmt::Result {
+    write_header(w, name, help, "counter")?;
+    writeln!(w, "{name} {}", counter.get())
+}
+
+fn write_gauge(w: &mut impl Write, name: &str, help: &str, gauge: &Gauge) -> fmt::Result {
+    if let Some(value) = gauge.get() {
+        write_header(w, name, help, "gauge")?;
+        writeln!(w, "{name} {value}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::String;
+
+    use super::*;
+
+    #[test]
+    fn worst_case_render_fits() {
+        for counter in [
+            &REQUESTS_SENT,
+            &REQUESTS_FAILED_NETWORK,
+            &REQUESTS_FAILED_HTTP_CODE,
+            &REQUESTS_FAILED_JSON_DECODING,
+            &REQUESTS_FAILED_UTF_DECODING,
+            &CONNECTIONS,
+            &RECONNECTS,
+            &STDOUT_DROPPED,
+            &LOG_DROPPED,
+        ] {
+            counter.add(u32::MAX);
+        }
+        for gauge in [&DHCP_TIME_MS, &RSSI, &STDIN_HIGH_WATER, &STDOUT_HIGH_WATER] {
+            gauge.set(Gauge::UNSET + 1);
+        }
+        // usize is 32 bits on the device.
+        let max = u32::MAX as usize;
+        let heap = HeapStats {
+            used: max,
+            free: max,
+            peak: max,
+            failed: u32::MAX,
+        };
+
+        let mut output = String::<MAX_RENDER_SIZE>::new();
+        render(&mut output, u64::MAX, Some(&heap)).unwrap();
+    }
+}
@@ -1,15 +1,26 @@
+pub mod diagnostics;
+pub mod firmware;
+pub mod lease;
+pub mod link;
+pub mod mdns;
 pub mod network_config;
+pub mod power;
+pub mod server;
+mod supervised;
+#[cfg(feature = "usb-ethernet")]
+pub mod usb_ethernet;
 
-use core::fmt::{Debug, Write};
+use core::cell::{Cell, RefCell};
+use core::fmt::{Debug, Display, Write};
 
 use cyw43::{Control, JoinOptions};
 use cyw43_pio::{PioSpi, DEFAULT_CLOCK_DIVIDER};
-use defmt::{info, unwrap};
+use defmt::unwrap;
 use embassy_executor::Spawner;
 use embassy_net::{
     dns::DnsSocket,
     tcp::client::{TcpClient, TcpClientState},
-    Config, DhcpConfig, Stack, StackResources, StaticConfigV4,
+    Config, ConfigV4, DhcpConfig, HardwareAddress, Stack, StackResources, StaticConfigV4,
 };
 use embassy_rp::{
     bind_interrupts,
@@ -18,9 +29,16 @@ use embassy_rp::{
     peripherals::{DMA_CH0, PIN_23, PIN_24, PIN_25, PIN_29, PIO0},
     pio::{InterruptHandler, Pio},
 };
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
 use embassy_time::{Duration, Instant, Timer};
-use heapless::{String, Vec};
+use embedded_io_async::BufRead;
+use firmware::FirmwareError;
+use heapless::{HistoryBuffer, String};
+use iot_device::metrics;
+use lease::LeaseWatcher;
+use link::{Bssid, LinkSample, HISTORY_LEN};
 use network_config::NetworkConfig;
+use power::PowerProfile;
 use rand::RngCore;
 use reqwless::{
     client::{HttpClient, TlsConfig, TlsVerify},
@@ -30,9 +48,10 @@ use reqwless::{
 use serde::Deserialize;
 use serde_json_core::de;
 use static_cell::StaticCell;
+use supervised::{SupervisedDriver, SupervisedSpi};
 use thiserror_no_std::Error;
 
-use crate::println;
+use crate::{log_debug, log_info, log_warn, println};
 
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => InterruptHandler<PIO0>;
@@ -45,19 +64,34 @@ bind_interrupts!(struct Irqs {
 
 const RX_BUFFER_SIZE: usize = 8192;
 
+/// The driver under the Wi-Fi network stack.
+type WifiDriver = SupervisedDriver<LeaseWatcher<cyw43::NetDriver<'static>>>;
+
 const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
 
+pub const MAX_HOSTNAME_LEN: usize = 32;
+
 pub struct Disconnected {
     control: Control<'static>,
 }
 
 pub struct Connected {
+    control: Mutex<NoopRawMutex, Control<'static>>,
     config: StaticConfigV4,
+    network_config: NetworkConfig,
+    /// The access point joined, once [`Client::sample_link`] has found it.
+    bssid: Cell<Option<Bssid>>,
+    /// Samples taken by [`Client::sample_link`], `None` where the access point wasn't seen.
+    link: RefCell<HistoryBuffer<Option<LinkSample>, HISTORY_LEN>>,
 }
 
 pub struct Client<T> {
     stack: Stack<'static>,
     seed: u64,
+    /// The name the device is advertised under, unique to each device.
+    hostname: String<MAX_HOSTNAME_LEN>,
+    /// See [`Client::set_power_profile`].
+    power: Cell<PowerProfile>,
     state: T,
 }
 
@@ -85,6 +119,23 @@ pub enum RequestError {
     UtfDecodingError(#[from] core::str::Utf8Error),
 }
 
+/// Convert `error` into a [`RequestError`], counting it in the [`metrics`].
+fn failed(error: impl Into<RequestError>) -> RequestError {
+    let error = error.into();
+    request_failed(&error);
+    error
+}
+
+/// Count a failed request under the kind of its [`RequestError`].
+fn request_failed(error: &RequestError) {
+    match error {
+        RequestError::NetworkError(_) => metrics::REQUESTS_FAILED_NETWORK.increment(),
+        RequestError::HttpCode(_) => metrics::REQUESTS_FAILED_HTTP_CODE.increment(),
+        RequestError::JsonDecodingError(_) => metrics::REQUESTS_FAILED_JSON_DECODING.increment(),
+        RequestError::UtfDecodingError(_) => metrics::REQUESTS_FAILED_UTF_DECODING.increment(),
+    }
+}
+
 impl From<reqwless::Error> for RequestError {
     fn from(value: reqwless::Error) -> Self {
         let mut buf = String::new();
@@ -95,16 +146,27 @@ impl From<reqwless::Error> for RequestError {
 
 #[embassy_executor::task]
 async fn cyw43_task(
-    runner: cyw43::Runner<'static, Output<'static>, PioSpi<'static, PIO0, 0, DMA_CH0>>,
+    runner: cyw43::Runner<
+        'static,
+        Output<'static>,
+        SupervisedSpi<PioSpi<'static, PIO0, 0, DMA_CH0>>,
+    >,
 ) -> ! {
     runner.run().await
 }
 
 #[embassy_executor::task]
-async fn net_task(mut runner: embassy_net::Runner<'static, cyw43::NetDriver<'static>>) -> ! {
+async fn net_task(mut runner: embassy_net::Runner<'static, WifiDriver>) -> ! {
     runner.run().await
 }
 
+/// Format `value` into a fixed capacity string, returning [`None`] if it does not fit.
+fn display_to_string<const N: usize>(value: impl Display) -> Option<String<N>> {
+    let mut buf = String::new();
+    write!(buf, "{value}").ok()?;
+    Some(buf)
+}
+
 impl<'a> Client<Disconnected> {
     #[allow(clippy::items_after_statements)]
     pub async fn new(
@@ -115,15 +177,10 @@ impl<'a> Client<Disconnected> {
         pin_29: PIN_29,
         pio0: PIO0,
         dma_ch0: DMA_CH0,
-    ) -> Self {
+    ) -> Result<Self, FirmwareError> {
         let mut rng = RoscRng;
 
-        // Use these if the firmware has not been preflashed to the pico.
-        // let fw = include_bytes!("../firmware/43439A0.bin");
-        // let clm = include_bytes!("../firmware/43439A0_clm.bin");
-
-        let fw = unsafe { core::slice::from_raw_parts(0x1010_0000 as *const u8, 2_303_211) };
-        let clm = unsafe { core::slice::from_raw_parts(0x1014_0000 as *const u8, 4752) };
+        let firmware::Blobs { firmware, clm } = firmware::load()?;
 
         let pwr = Output::new(pin_23, Level::Low);
         let cs = Output::new(pin_25, Level::High);
@@ -141,13 +198,11 @@ impl<'a> Client<Disconnected> {
 
         static STATE: StaticCell<cyw43::State> = StaticCell::new();
         let state = STATE.init(cyw43::State::new());
-        let (net_device, mut control, runner) = cyw43::new(state, pwr, spi, fw).await;
+        let (net_device, mut control, runner) =
+            cyw43::new(state, pwr, SupervisedSpi(spi), firmware).await;
         unwrap!(spawner.spawn(cyw43_task(runner)));
 
         control.init(clm).await;
-        control
-            .set_power_management(cyw43::PowerManagementMode::PowerSave)
-            .await;
 
         let config = Config::dhcpv4(DhcpConfig::default());
 
@@ -155,10 +210,10 @@ impl<'a> Client<Disconnected> {
         let seed = rng.next_u64();
 
         // Init network stack
-        static RESOURCES: StaticCell<StackResources<5>> = StaticCell::new();
+        static RESOURCES: StaticCell<StackResources<6>> = StaticCell::new();
 
         let (stack, runner) = embassy_net::new(
-            net_device,
+            SupervisedDriver::new(LeaseWatcher(net_device)),
             config,
             RESOURCES.init(StackResources::new()),
             seed,
@@ -166,24 +221,82 @@ impl<'a> Client<Disconnected> {
 
         unwrap!(spawner.spawn(net_task(runner)));
 
-        info!("Network stack initialised");
+        log_info!("Network stack initialised");
 
-        info!("{}", stack.hardware_address());
+        log_info!("{}", stack.hardware_address());
 
-        Self {
+        let mut client = Self {
             state: Disconnected { control },
             stack,
             seed,
+            hostname: String::new(),
+            power: Cell::new(PowerProfile::default()),
+        };
+        client.hostname = client.default_hostname();
+        client.set_power_profile(PowerProfile::default()).await;
+        Ok(client)
+    }
+
+    /// Derive a hostname from the last three bytes of the MAC address, eg: `pico-a1b2c3`.
+    fn default_hostname(&self) -> String<MAX_HOSTNAME_LEN> {
+        let HardwareAddress::Ethernet(mac_address) = self.stack.hardware_address();
+        let mut hostname = String::new();
+        write!(
+            hostname,
+            "pico-{:02x}{:02x}{:02x}",
+            mac_address.0[3], mac_address.0[4], mac_address.0[5]
+        )
+        .expect("Failed to write to hostname buffer");
+        hostname
+    }
+
+    /// Check the gateway answers on the cached lease `config`, otherwise drop it and go through DHCP instead, returning
+    /// whether the lease is kept.
+    ///
+    /// An address which has been given to someone else can't reach the gateway, nor can a lapsed one in most networks.
+    async fn keep_cached_lease(
+        &self,
+        network_config: &NetworkConfig,
+        config: &StaticConfigV4,
+    ) -> bool {
+        if diagnostics::gateway_answers(self.stack, config).await {
+            log_info!("Reusing the cached DHCP lease");
+            return true;
         }
+
+        log_info!("The gateway didn't answer on the cached DHCP lease, asking for a new one");
+        lease::forget().await;
+        self.stack.set_config_v4(ConfigV4::Dhcp(
+            network_config.dhcp.to_dhcp_config(&self.hostname),
+        ));
+        false
     }
 
     pub async fn connect(
         mut self,
         network_config: &NetworkConfig,
     ) -> Result<Client<Connected>, (ConnectionError, Self)> {
-        if let Some(ip_config) = &network_config.ip_config {
+        self.hostname = network_config
+            .dhcp
+            .hostname
+            .clone()
+            .unwrap_or_else(|| self.default_hostname());
+
+        // Duty cycled devices reconnect often enough for the DHCP exchange to dominate their time awake.
+        let mut cached_lease =
+            if network_config.ip_config.is_none() && self.power_profile().is_duty_cycled() {
+                lease::cached(network_config).await
+            } else {
+                None
+            };
+
+        if let Some(ip_config) = network_config.ip_config.as_ref().or(cached_lease.as_ref()) {
             self.stack
-                .set_config_v4(embassy_net::ConfigV4::Static(ip_config.clone()));
+                .set_config_v4(ConfigV4::Static(ip_config.clone()));
+        } else {
+            self.stack.set_config_v4(ConfigV4::Dhcp(
+                network_config.dhcp.to_dhcp_config(&self.hostname),
+            ));
         }
 
         if let Err(error) = {
@@ -202,7 +315,7 @@ impl<'a> Client<Disconnected> {
                     .await
             }
         } {
-            info!("join failed with status={}", error.status);
+            log_warn!("join failed with status={}", error.status);
             let error = match error.status {
                 2 => ConnectionError::OtherTimeout,
                 3 => ConnectionError::SsidNotFound,
@@ -212,7 +325,13 @@ impl<'a> Client<Disconnected> {
         }
 
         println!("waiting for DHCP...");
-        let start = Instant::now();
+        let mut start = Instant::now();
+        if let Some(config) = cached_lease.as_ref() {
+            if !self.keep_cached_lease(network_config, config).await {
+                cached_lease = None;
+                start = Instant::now();
+            }
+        }
         while !self.stack.is_config_up() {
             Timer::after_millis(100).await;
             let now = Instant::now();
@@ -220,6 +339,13 @@ impl<'a> Client<Disconnected> {
                 return Err((ConnectionError::DhcpTimeout, self));
             }
         }
+        if cached_lease.is_none() && network_config.ip_config.is_none() {
+            let elapsed = (Instant::now() - start).as_millis();
+            metrics::DHCP_TIME_MS.set(i32::try_from(elapsed).unwrap_or(i32::MAX));
+            if let Some(config) = self.stack.config_v4() {
+                lease::remember(network_config, &config).await;
+            }
+        }
         println!("DHCP is now up!");
 
         println!("waiting for link up...");
@@ -239,17 +365,55 @@ impl<'a> Client<Disconnected> {
 
         self.state.control.gpio_set(0, true).await;
 
+        if metrics::CONNECTIONS.get() > 0 {
+            metrics::RECONNECTS.increment();
+        }
+        metrics::CONNECTIONS.increment();
+
         Ok(Client {
             state: Connected {
+                control: Mutex::new(self.state.control),
                 config: self.stack.config_v4().unwrap(),
+                network_config: network_config.clone(),
+                bssid: Cell::new(None),
+                link: RefCell::new(HistoryBuffer::new()),
             },
             stack: self.stack,
             seed: self.seed,
+            hostname: self.hostname,
+            power: self.power,
         })
     }
 }
 
 impl Client<Connected> {
+    /// Leave the current network, returning a client which can be connected again.
+    pub async fn disconnect(self) -> Client<Disconnected> {
+        let mut control = self.state.control.into_inner();
+        control.leave().await;
+        control.gpio_set(0, false).await;
+
+        Client {
+            state: Disconnected { control },
+            stack: self.stack,
+            seed: self.seed,
+            hostname: self.hostname,
+            power: self.power,
+        }
+    }
+
+    /// The stack requests are sent over, Wi-Fi unless the USB link is preferred.
+    #[cfg_attr(not(feature = "usb-ethernet"), allow(clippy::missing_const_for_fn))]
+    fn uplink(&self) -> Stack<'static> {
+        #[cfg(feature = "usb-ethernet")]
+        if usb_ethernet::preferred() {
+            if let Some(stack) = usb_ethernet::stack() {
+                return stack;
+            }
+        }
+        self.stack
+    }
+
     pub const BLANK_REQUEST_BUFFER: String<RX_BUFFER_SIZE> = String::new();
 
     /// Send a http/s request and serialize the returning data.
@@ -263,9 +427,17 @@ impl Client<Connected> {
         body: Option<&'a str>,
         serialization_buffer: &'a mut String<RX_BUFFER_SIZE>,
     ) -> Result<(StatusCode, T), RequestError> {
-        let result = self.inner_request(url, method, headers, body).await?;
-        *serialization_buffer = result.1;
-        Ok((result.0, serde_json_core::from_str(serialization_buffer)?.0))
+        let status = self
+            .inner_request(url, method, headers, body, serialization_buffer)
+            .await?;
+        match serde_json_core::from_str(serialization_buffer) {
+            Ok((data, _)) => Ok((status, data)),
+            Err(error) => {
+                let error = error.into();
+                request_failed(&error);
+                Err(error)
+            }
+        }
     }
 
     /// Send a http/s request but ignore the returned response body.
@@ -278,24 +450,73 @@ impl Client<Connected> {
         headers: Option<&[(&str, &str)]>,
         body: Option<&'a str>,
     ) -> Result<StatusCode, RequestError> {
-        let response = self.inner_request(url, method, headers, body).await?;
-        Ok(response.0)
+        self.send_request(url, method, headers, body, |_| Ok::<_, RequestError>(()))
+            .await
     }
 
+    /// Send a request through [`send_request`], collecting the response body into `buffer`.
     async fn inner_request<'a>(
         &self,
         url: &str,
         method: Method,
         headers: Option<&[(&str, &str)]>,
         body: Option<&'a str>,
-    ) -> Result<(StatusCode, String<RX_BUFFER_SIZE>), RequestError> {
+        buffer: &mut String<RX_BUFFER_SIZE>,
+    ) -> Result<StatusCode, RequestError> {
+        buffer.clear();
+        // Safety: the body is checked to be utf8 below, and cleared if it isn't, before the string is used again.
+        let bytes = unsafe { buffer.as_mut_vec() };
+        let status = self
+            .send_request(url, method, headers, body, |chunk| {
+                bytes
+                    .extend_from_slice(chunk)
+                    .map_err(|()| failed(reqwless::Error::BufferTooSmall))
+            })
+            .await;
+        let status = status.and_then(|status| {
+            core::str::from_utf8(bytes).map_err(failed)?;
+            Ok(status)
+        });
+        if status.is_err() {
+            bytes.clear();
+        }
+        let status = status?;
+
+        log_debug!("Response body: {buffer:?}");
+        Ok(status)
+    }
+
+    /// Stream the body of a GET request to `url` into `sink`, one chunk at a time.
+    pub async fn download<E: From<RequestError>>(
+        &self,
+        url: &str,
+        sink: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        self.send_request(url, Method::GET, None, None, sink)
+            .await
+            .map(|_| ())
+    }
+
+    /// Send a http/s request, passing the response body to `sink` as it arrives.
+    ///
+    /// Failures of the request itself are recorded in the [`metrics`].
+    async fn send_request<E: From<RequestError>>(
+        &self,
+        url: &str,
+        method: Method,
+        headers: Option<&[(&str, &str)]>,
+        body: Option<&str>,
+        mut sink: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<StatusCode, E> {
+        metrics::REQUESTS_SENT.increment();
+
         let mut rx_buffer = [0; RX_BUFFER_SIZE];
         let mut tls_read_buffer = [0; 16640];
         let mut tls_write_buffer = [0; 16640];
 
         let client_state = TcpClientState::<1, 1024, 1024>::new();
-        let tcp_client = TcpClient::new(self.stack, &client_state);
-        let dns_client = DnsSocket::new(self.stack);
+        let tcp_client = TcpClient::new(self.uplink(), &client_state);
+        let dns_client = DnsSocket::new(self.uplink());
         let tls_config = TlsConfig::new(
             self.seed,
             &mut tls_read_buffer,
@@ -310,32 +531,41 @@ impl Client<Connected> {
         };
 
         // Create request with headers and body.
-        let mut request = http_client.request(method, url).await?;
+        let mut request = http_client.request(method, url).await.map_err(failed)?;
         if let Some(headers) = headers {
             request = request.headers(headers);
         }
         let mut request = request.body(body.map(str::as_bytes));
 
-        info!("connecting to {}", &url);
+        log_debug!("connecting to {url}");
 
         // Send request.
-        let response = request.send(&mut rx_buffer).await?;
+        let response = request.send(&mut rx_buffer).await.map_err(failed)?;
         let status = response.status;
         if !status.is_successful() {
-            return Err(RequestError::HttpCode(status.0));
+            return Err(failed(RequestError::HttpCode(status.0)).into());
         }
 
-        // Serialize data as string.
-        let body = response.body().read_to_end().await?;
-        let buffer = String::from_utf8(Vec::from_slice(body).unwrap())?;
+        // Pass the body on as it is read, straight out of the receive buffer.
+        let mut reader = response.body().reader();
+        loop {
+            let chunk = reader.fill_buf().await.map_err(failed)?;
+            if chunk.is_empty() {
+                break;
+            }
+            let length = chunk.len();
+            sink(chunk)?;
+            reader.consume(length);
+        }
 
-        info!("Response body: {:?}", buffer);
-        Ok((status, buffer))
+        Ok(status)
     }
 
     pub async fn print_config(&self) {
         println!("~~~Config~~~");
 
+        println!("Hostname: {}.local", self.hostname);
+
         println!("Address: {}", self.state.config.address);
 
         if let Some(gateway) = self.state.config.gateway {
@@ -0,0 +1,219 @@
+use embassy_futures::select::{select, Either};
+use embassy_net::{
+    dns::{self, DnsQueryType},
+    driver::Driver,
+    raw::{IpProtocol, IpVersion, PacketMetadata, RawSocket},
+    IpAddress, Ipv4Address, Stack, StaticConfigV4,
+};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    wire::{Icmpv4Packet, Icmpv4Repr, Ipv4Packet, Ipv4Repr},
+};
+
+use super::{Client, Connected, WifiDriver};
+
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+const PING_PAYLOAD: &[u8] = b"iot-device ping";
+const PACKET_SIZE: usize = 128;
+
+/// Identifies echo replies meant for this device, "PI" in ascii.
+const PING_IDENT: u16 = 0x5049;
+/// How many echo requests [`gateway_answers`] sends, the first may be lost while the gateway's MAC is resolved.
+const GATEWAY_ATTEMPTS: u16 = 3;
+
+/// The result of [`Client::ping`].
+pub struct PingStats {
+    pub transmitted: u16,
+    pub received: u16,
+    pub min: Option<Duration>,
+    pub max: Option<Duration>,
+    total: Duration,
+}
+
+impl PingStats {
+    pub fn average(&self) -> Option<Duration> {
+        (self.received > 0).then(|| self.total / u32::from(self.received))
+    }
+
+    /// The share of echo requests that went unanswered, as a percentage.
+    pub fn loss(&self) -> u16 {
+        if self.transmitted == 0 {
+            return 0;
+        }
+        let lost = u32::from(self.transmitted - self.received);
+        u16::try_from(lost * 100 / u32::from(self.transmitted)).unwrap_or(100)
+    }
+
+    fn record(&mut self, rtt: Duration) {
+        self.received += 1;
+        self.total += rtt;
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.max = Some(self.max.map_or(rtt, |max| max.max(rtt)));
+    }
+}
+
+impl Client<Connected> {
+    /// Send `count` ICMP echo requests to `address` over Wi-Fi, one per second, and measure the round trip times.
+    pub async fn ping(&self, address: Ipv4Address, count: u16) -> PingStats {
+        ping::<WifiDriver>(
+            self.stack,
+            self.state.config.address.address(),
+            address,
+            count,
+            false,
+        )
+        .await
+    }
+
+    /// Resolve `name` to its IPv4 addresses using the DNS servers of the current config.
+    pub async fn lookup(&self, name: &str) -> Result<Vec<Ipv4Address, 4>, dns::Error> {
+        let addresses = dns::DnsSocket::new(self.stack)
+            .query(name, DnsQueryType::A)
+            .await?;
+
+        Ok(addresses
+            .iter()
+            .map(|address| match address {
+                IpAddress::Ipv4(address) => *address,
+            })
+            .take(4)
+            .collect())
+    }
+}
+
+/// Whether the gateway of `config` answers an echo request from its address on the Wi-Fi `stack`, to check the address
+/// is still ours to use.
+pub(super) async fn gateway_answers(stack: Stack<'static>, config: &StaticConfigV4) -> bool {
+    let Some(gateway) = config.gateway else {
+        return false;
+    };
+    ping::<WifiDriver>(
+        stack,
+        config.address.address(),
+        gateway,
+        GATEWAY_ATTEMPTS,
+        true,
+    )
+    .await
+    .received
+        > 0
+}
+
+/// Send up to `count` ICMP echo requests from `source` to `address`, one per second, stopping at the first reply if
+/// `until_reply` is set. `D` is the driver under `stack`.
+async fn ping<D: Driver>(
+    stack: Stack<'static>,
+    source: Ipv4Address,
+    address: Ipv4Address,
+    count: u16,
+    until_reply: bool,
+) -> PingStats {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; PACKET_SIZE * 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; PACKET_SIZE * 2];
+    let socket = RawSocket::new::<D>(
+        stack,
+        IpVersion::Ipv4,
+        IpProtocol::Icmp,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    let mut packet = [0; PACKET_SIZE];
+    let mut stats = PingStats {
+        transmitted: 0,
+        received: 0,
+        min: None,
+        max: None,
+        total: Duration::from_ticks(0),
+    };
+
+    for seq_no in 0..count {
+        if seq_no > 0 {
+            Timer::after(PING_INTERVAL).await;
+        }
+
+        let length = echo_request(source, address, seq_no, &mut packet);
+        let sent = Instant::now();
+        socket.send(&packet[..length]).await;
+        stats.transmitted += 1;
+
+        let reply = async {
+            loop {
+                let Ok(length) = socket.recv(&mut packet).await else {
+                    continue;
+                };
+                if is_echo_reply(&packet[..length], address, seq_no) {
+                    break Instant::now() - sent;
+                }
+            }
+        };
+
+        if let Either::First(rtt) = select(reply, Timer::after(PING_TIMEOUT)).await {
+            stats.record(rtt);
+            if until_reply {
+                break;
+            }
+        }
+    }
+
+    stats
+}
+
+/// Write an IPv4 packet containing an ICMP echo request into `buffer`, returning its length.
+fn echo_request(
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    seq_no: u16,
+    buffer: &mut [u8],
+) -> usize {
+    let checksum = ChecksumCapabilities::default();
+    let icmp_repr = Icmpv4Repr::EchoRequest {
+        ident: PING_IDENT,
+        seq_no,
+        data: PING_PAYLOAD,
+    };
+    let ip_repr = Ipv4Repr {
+        src_addr: source,
+        dst_addr: destination,
+        next_header: IpProtocol::Icmp,
+        payload_len: icmp_repr.buffer_len(),
+        hop_limit: 64,
+    };
+
+    let length = ip_repr.buffer_len() + icmp_repr.buffer_len();
+    let mut ip_packet = Ipv4Packet::new_unchecked(&mut buffer[..length]);
+    ip_repr.emit(&mut ip_packet, &checksum);
+    icmp_repr.emit(
+        &mut Icmpv4Packet::new_unchecked(ip_packet.payload_mut()),
+        &checksum,
+    );
+
+    length
+}
+
+fn is_echo_reply(packet: &[u8], source: Ipv4Address, expected_seq_no: u16) -> bool {
+    let checksum = ChecksumCapabilities::default();
+    let Ok(ip_packet) = Ipv4Packet::new_checked(packet) else {
+        return false;
+    };
+    let Ok(ip_repr) = Ipv4Repr::parse(&ip_packet, &checksum) else {
+        return false;
+    };
+    let Ok(icmp_packet) = Icmpv4Packet::new_checked(ip_packet.payload()) else {
+        return false;
+    };
+
+    ip_repr.src_addr == source
+        && matches!(
+            Icmpv4Repr::parse(&icmp_packet, &checksum),
+            Ok(Icmpv4Repr::EchoReply { ident, seq_no, .. })
+                if ident == PING_IDENT && seq_no == expected_seq_no
+        )
+}
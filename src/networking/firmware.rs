@@ -0,0 +1,81 @@
+//! The CYW43 firmware and CLM blobs.
+//!
+//! With the `embedded-firmware` feature, on by default, the blobs in `firmware/` are included in the binary.
+//! Otherwise they are read from the `CYW43_FIRMWARE` region reserved in `memory.x`, which is flashed once with:
+//!
+//! ```sh
+//! probe-rs download firmware/43439A0.bin --binary-format bin --chip RP2040 --base-address 0x101BC000
+//! probe-rs download firmware/43439A0_clm.bin --binary-format bin --chip RP2040 --base-address 0x101F8000
+//! ```
+//!
+//! Use `--chip RP235x` for the Pico 2 W.
+
+use thiserror_no_std::Error;
+
+pub struct Blobs {
+    pub firmware: &'static [u8],
+    pub clm: &'static [u8],
+}
+
+#[derive(Debug, Error)]
+#[cfg_attr(feature = "embedded-firmware", allow(dead_code))]
+pub enum FirmwareError {
+    #[error("The preflashed {name} blob at {address:#x} does not match `firmware/`, it may not have been flashed")]
+    Mismatch { name: &'static str, address: usize },
+}
+
+#[cfg(feature = "embedded-firmware")]
+#[allow(clippy::unnecessary_wraps)]
+pub const fn load() -> Result<Blobs, FirmwareError> {
+    Ok(Blobs {
+        firmware: include_bytes!("../../firmware/43439A0.bin"),
+        clm: include_bytes!("../../firmware/43439A0_clm.bin"),
+    })
+}
+
+#[cfg(not(feature = "embedded-firmware"))]
+pub use preflashed::load;
+
+#[cfg(not(feature = "embedded-firmware"))]
+#[allow(clippy::unreadable_literal)]
+mod preflashed {
+    use super::{Blobs, FirmwareError};
+    use crate::storage::fnv1a;
+
+    // Lengths and checksums of the blobs in `firmware/`, generated by `build.rs`.
+    include!(concat!(env!("OUT_DIR"), "/cyw43_firmware.rs"));
+
+    /// Addresses of the blobs within the `CYW43_FIRMWARE` region, must match `memory.x` and the commands above.
+    const FIRMWARE_ADDRESS: usize = 0x101B_C000;
+    const CLM_ADDRESS: usize = 0x101F_8000;
+
+    pub fn load() -> Result<Blobs, FirmwareError> {
+        Ok(Blobs {
+            firmware: read(
+                "firmware",
+                FIRMWARE_ADDRESS,
+                FIRMWARE_LEN,
+                FIRMWARE_CHECKSUM,
+            )?,
+            clm: read("CLM", CLM_ADDRESS, CLM_LEN, CLM_CHECKSUM)?,
+        })
+    }
+
+    /// Read a blob from flash, checking it matches the one it was built against so a missing or stale blob is
+    /// never handed to the chip.
+    fn read(
+        name: &'static str,
+        address: usize,
+        length: usize,
+        checksum: u32,
+    ) -> Result<&'static [u8], FirmwareError> {
+        // SAFETY: The region is reserved in `memory.x`, so it is always mapped and never written by the application.
+        let blob = unsafe { core::slice::from_raw_parts(address as *const u8, length) };
+
+        if fnv1a(blob) == checksum {
+            Ok(blob)
+        } else {
+            Err(FirmwareError::Mismatch { name, address })
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! The last DHCP lease, reused as a static config to skip the DHCP exchange when reconnecting.
+//!
+//! embassy-net can't start DHCP from a known address or report the lease time and server, so the address, gateway
+//! and DNS servers are reused as they are until the lease would normally be renewed. The lease time is taken from the
+//! server's ACK, which [`LeaseWatcher`] picks out of the frames on their way to the stack, as the server won't give
+//! the address to anyone else before then. [`Client::connect`](super::Client::connect) also checks the gateway answers
+//! before relying on a lease.
+//!
+//! The lease is kept in the `LEASE` region of RAM, which isn't zeroed on boot nor used by the bootloader, so it
+//! survives the resets [`crate::system::sleep`] wakes with, but not a power cycle, after which there's no telling how
+//! long the device was off for.
+
+use core::cell::Cell;
+use core::mem::{offset_of, size_of, MaybeUninit};
+use core::ptr::addr_of_mut;
+use core::task::Context;
+
+use embassy_net::{
+    driver::{Capabilities, Driver, HardwareAddress, LinkState, RxToken},
+    Ipv4Address, Ipv4Cidr, StaticConfigV4,
+};
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    mutex::Mutex,
+};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+use smoltcp::wire::{
+    DhcpMessageType, DhcpPacket, DhcpRepr, EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Packet,
+    UdpPacket, DHCP_CLIENT_PORT, DHCP_SERVER_PORT,
+};
+
+use super::network_config::NetworkConfig;
+use crate::log_info;
+use crate::storage::fnv1a;
+
+/// The longest lease kept, the whole record must fit in the `LEASE` region in memory.x.
+const DATA_SIZE: usize = 256;
+/// Marks the record as written by [`suspend`], "DHCP" in ascii.
+const MAGIC: u32 = 0x4448_4350;
+
+#[repr(C)]
+struct Record {
+    magic: u32,
+    /// [`fnv1a`] of the data, as the record holds garbage after a power cycle.
+    checksum: u32,
+    length: u32,
+    /// The [`Lease`] as json.
+    data: [u8; DATA_SIZE],
+}
+
+#[link_section = ".lease"]
+static mut RECORD: MaybeUninit<Record> = MaybeUninit::uninit();
+
+static LEASE: Mutex<CriticalSectionRawMutex, Option<Lease>> = Mutex::new(None);
+
+/// The last lease the server acknowledged, seen by [`LeaseWatcher`].
+static GRANTED: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Option<Granted>>> =
+    blocking_mutex::Mutex::new(Cell::new(None));
+
+#[derive(Clone, Copy)]
+struct Granted {
+    address: Ipv4Address,
+    /// The lease time given by the server, in seconds.
+    lease_time: u32,
+    /// When the server asked for the lease to be renewed, in seconds, if it did.
+    renew_time: Option<u32>,
+    acked_at: Instant,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Lease {
+    /// [`fnv1a`] of the SSID the lease was given on.
+    network: u32,
+    address: [u8; 4],
+    prefix_len: u8,
+    gateway: Option<[u8; 4]>,
+    dns_servers: Vec<[u8; 4], 3>,
+    /// Seconds since boot after which the lease is no longer reused.
+    renew_at: u64,
+}
+
+impl Lease {
+    fn config(&self) -> StaticConfigV4 {
+        StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Address::from(self.address), self.prefix_len),
+            gateway: self.gateway.map(Ipv4Address::from),
+            dns_servers: self
+                .dns_servers
+                .iter()
+                .copied()
+                .map(Ipv4Address::from)
+                .collect(),
+        }
+    }
+}
+
+/// Take the lease left by [`suspend`], clearing it so it's only picked up once.
+pub async fn init() {
+    let pointer = addr_of_mut!(RECORD).cast::<Record>();
+    // Safety: the record is only read as bytes, and only `suspend` writes to it otherwise.
+    let bytes: [u8; size_of::<Record>()] = unsafe {
+        let bytes = crate::system::read_noinit(pointer.cast());
+        addr_of_mut!((*pointer).magic).write_volatile(0);
+        bytes
+    };
+
+    let field = |offset: usize| {
+        u32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap_or_default())
+    };
+    let length = field(offset_of!(Record, length)) as usize;
+    let data = &bytes[offset_of!(Record, data)..][..DATA_SIZE];
+    if field(offset_of!(Record, magic)) != MAGIC
+        || length > DATA_SIZE
+        || fnv1a(&data[..length]) != field(offset_of!(Record, checksum))
+    {
+        return;
+    }
+
+    if let Ok((lease, _)) = serde_json_core::from_slice::<Lease>(&data[..length]) {
+        *LEASE.lock().await = Some(lease);
+    }
+}
+
+/// The lease last given on `network_config`'s network, if it can still be reused.
+pub(super) async fn cached(network_config: &NetworkConfig) -> Option<StaticConfigV4> {
+    LEASE
+        .lock()
+        .await
+        .as_ref()
+        .filter(|lease| lease.network == fnv1a(network_config.ssid.as_bytes()))
+        .filter(|lease| Instant::now().as_secs() < lease.renew_at)
+        .map(Lease::config)
+}
+
+/// Keep the lease just given by the DHCP server, to be reused until it's due to be renewed, if the server's ACK was
+/// seen with a lease time.
+pub(super) async fn remember(network_config: &NetworkConfig, config: &StaticConfigV4) {
+    let Some(granted) = GRANTED
+        .lock(Cell::get)
+        .filter(|granted| granted.address == config.address.address())
+    else {
+        return;
+    };
+    // Renewed the same way smoltcp would, at T1 or half way through the lease, capped by `max_lease_duration`.
+    let mut lease_time = Duration::from_secs(granted.lease_time.into());
+    if let Some(max_lease_duration) = network_config.dhcp.max_lease_duration {
+        lease_time = lease_time.min(max_lease_duration);
+    }
+    let renew_after = granted
+        .renew_time
+        .map_or(lease_time, |renew_time| {
+            Duration::from_secs(renew_time.into())
+        })
+        .min(lease_time / 2);
+
+    *LEASE.lock().await = Some(Lease {
+        network: fnv1a(network_config.ssid.as_bytes()),
+        address: config.address.address().octets(),
+        prefix_len: config.address.prefix_len(),
+        gateway: config.gateway.map(|gateway| gateway.octets()),
+        dns_servers: config.dns_servers.iter().map(Ipv4Address::octets).collect(),
+        renew_at: (granted.acked_at + renew_after).as_secs(),
+    });
+}
+
+/// Stop reusing the lease, so the next connection goes through DHCP.
+pub(super) async fn forget() {
+    if LEASE.lock().await.take().is_some() {
+        log_info!("Dropped the cached DHCP lease");
+    }
+}
+
+/// Keep the lease across the reset at the end of a sleep lasting `duration`, see [`init`].
+pub async fn suspend(duration: Duration) {
+    let Some(mut lease) = LEASE.lock().await.take() else {
+        return;
+    };
+    // Uptime starts over after the reset.
+    let elapsed = (Instant::now() + duration).as_secs();
+    lease.renew_at = lease.renew_at.saturating_sub(elapsed);
+    if lease.renew_at == 0 {
+        return;
+    }
+
+    let mut data = [0; DATA_SIZE];
+    let Ok(length) = serde_json_core::to_slice(&lease, &mut data) else {
+        return;
+    };
+
+    let pointer = addr_of_mut!(RECORD).cast::<Record>();
+    #[allow(clippy::cast_possible_truncation)]
+    let record = Record {
+        magic: MAGIC,
+        checksum: fnv1a(&data[..length]),
+        length: length as u32,
+        data,
+    };
+    // Safety: nothing else writes to the record, which is only read back by `init` after the reset.
+    unsafe { pointer.write(record) };
+}
+
+/// A wrapper around the Wi-Fi driver which picks the DHCP server's ACKs for this device out of the frames it
+/// receives, to learn the lease time embassy-net doesn't report.
+pub struct LeaseWatcher<D>(pub D);
+
+impl<D: Driver> Driver for LeaseWatcher<D> {
+    type RxToken<'a>
+        = WatchedRxToken<D::RxToken<'a>>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = D::TxToken<'a>
+    where
+        Self: 'a;
+
+    fn receive(&mut self, cx: &mut Context<'_>) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let address = match self.0.hardware_address() {
+            HardwareAddress::Ethernet(address) => Some(address),
+            _ => None,
+        };
+        self.0
+            .receive(cx)
+            .map(|(rx, tx)| (WatchedRxToken(rx, address), tx))
+    }
+
+    fn transmit(&mut self, cx: &mut Context<'_>) -> Option<Self::TxToken<'_>> {
+        self.0.transmit(cx)
+    }
+
+    fn link_state(&mut self, cx: &mut Context<'_>) -> LinkState {
+        self.0.link_state(cx)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.0.capabilities()
+    }
+
+    fn hardware_address(&self) -> HardwareAddress {
+        self.0.hardware_address()
+    }
+}
+
+/// A received frame, checked for a DHCP ACK to the MAC address it holds before it's handed to the stack.
+pub struct WatchedRxToken<T>(T, Option<[u8; 6]>);
+
+impl<T: RxToken> RxToken for WatchedRxToken<T> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let Self(token, address) = self;
+        token.consume(|frame| {
+            if let Some(granted) = address.and_then(|address| dhcp_ack(frame, address)) {
+                GRANTED.lock(|cell| cell.set(Some(granted)));
+            }
+            f(frame)
+        })
+    }
+}
+
+/// The lease in `frame`, if it's a DHCP ACK with a lease time for the client with the MAC `address`.
+fn dhcp_ack(frame: &[u8], address: [u8; 6]) -> Option<Granted> {
+    let frame = EthernetFrame::new_checked(frame).ok()?;
+    if frame.ethertype() != EthernetProtocol::Ipv4 {
+        return None;
+    }
+    let packet = Ipv4Packet::new_checked(frame.payload()).ok()?;
+    if packet.next_header() != IpProtocol::Udp {
+        return None;
+    }
+    let datagram = UdpPacket::new_checked(packet.payload()).ok()?;
+    if datagram.src_port() != DHCP_SERVER_PORT || datagram.dst_port() != DHCP_CLIENT_PORT {
+        return None;
+    }
+    let message = DhcpPacket::new_checked(datagram.payload()).ok()?;
+    let repr = DhcpRepr::parse(&message).ok()?;
+    if repr.message_type != DhcpMessageType::Ack || repr.client_hardware_address.0 != address {
+        return None;
+    }
+
+    Some(Granted {
+        address: repr.your_ip,
+        lease_time: repr.lease_duration?,
+        renew_time: repr.renew_duration,
+        acked_at: Instant::now(),
+    })
+}
@@ -0,0 +1,129 @@
+//! Link quality of the joined network, sampled periodically and kept in a short history.
+
+use core::fmt;
+
+use cyw43::{ScanOptions, ScanType};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use super::{Client, Connected};
+use iot_device::metrics;
+
+/// How many samples are kept.
+pub const HISTORY_LEN: usize = 16;
+
+/// How long each channel is probed for, which keeps the scan, and so the hold on the control, to about half a second.
+const DWELL_TIME: Duration = Duration::from_millis(40);
+
+/// A MAC address identifying an access point.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Bssid(pub [u8; 6]);
+
+impl fmt::Display for Bssid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, byte) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(":")?;
+            }
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The state of the link at one point in time, from [`Client::sample_link`].
+#[derive(Clone, Copy)]
+pub struct LinkSample {
+    /// When the sample was taken, in seconds since boot.
+    pub uptime: u64,
+    /// Signal strength in dBm.
+    pub rssi: i16,
+    pub channel: u8,
+    pub bssid: Bssid,
+    /// The fastest rate the access point offers in kbps, as cyw43 doesn't expose the current transmit rate.
+    pub max_rate: u32,
+}
+
+impl Client<Connected> {
+    /// Sample the link to the joined access point and add it to the history, `None` if it couldn't be seen.
+    ///
+    /// cyw43 doesn't report which access point it joined, which is the strongest one with the network's SSID, so the
+    /// first sample keeps the strongest result and later ones only scan for its BSSID.
+    pub async fn sample_link(&self) -> Option<LinkSample> {
+        let joined = self.state.bssid.get();
+        let mut scan_options = ScanOptions::default();
+        scan_options.ssid = Some(self.state.network_config.ssid.clone());
+        scan_options.bssid = joined.map(|bssid| bssid.0);
+        scan_options.scan_type = ScanType::Active;
+        scan_options.dwell_time = Some(DWELL_TIME);
+
+        let mut control = self.state.control.lock().await;
+        let mut scanner = control.scan(scan_options).await;
+
+        let mut sample: Option<LinkSample> = None;
+        while let Some(bss) = scanner.next().await {
+            // Keep the strongest result, as multiple access points may share the SSID.
+            if sample.is_some_and(|sample| sample.rssi >= bss.rssi)
+                || joined.is_some_and(|joined| joined.0 != bss.bssid)
+            {
+                continue;
+            }
+
+            let rates = &bss.rates[..(bss.rateset_count as usize).min(bss.rates.len())];
+            sample = Some(LinkSample {
+                uptime: Instant::now().as_secs(),
+                rssi: bss.rssi,
+                // The low byte of the chanspec is the channel number.
+                channel: bss.chanspec.to_le_bytes()[0],
+                bssid: Bssid(bss.bssid),
+                // Rates are in units of 500kbps, with the top bit marking the basic rates.
+                max_rate: rates
+                    .iter()
+                    .map(|rate| u32::from(rate & 0x7f) * 500)
+                    .max()
+                    .unwrap_or_default(),
+            });
+        }
+        drop(scanner);
+        drop(control);
+
+        if let Some(sample) = sample {
+            metrics::RSSI.set(sample.rssi.into());
+            self.state.bssid.set(Some(sample.bssid));
+        }
+        self.state.link.borrow_mut().write(sample);
+        sample
+    }
+
+    /// The samples taken since joining the network in which the access point was seen, oldest first.
+    pub fn link_history(&self) -> Vec<LinkSample, HISTORY_LEN> {
+        self.state
+            .link
+            .borrow()
+            .oldest_ordered()
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    /// The signal strength from the latest sample, `None` if the access point wasn't seen in it.
+    pub fn rssi(&self) -> Option<i16> {
+        self.state
+            .link
+            .borrow()
+            .recent()
+            .copied()
+            .flatten()
+            .map(|sample| sample.rssi)
+    }
+
+    /// Whether each of the latest `count` samples was weaker than `threshold` dBm or missed the access point.
+    pub fn link_below(&self, threshold: i16, count: usize) -> bool {
+        let history = self.state.link.borrow();
+        history.len() >= count
+            && history
+                .oldest_ordered()
+                .skip(history.len() - count)
+                .all(|sample| sample.is_none_or(|sample| sample.rssi < threshold))
+    }
+}
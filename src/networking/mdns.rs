@@ -0,0 +1,92 @@
+//! Runs the mDNS responder from [`iot_device::mdns`] on the Wi-Fi network.
+
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    IpEndpoint, Ipv4Address,
+};
+use embassy_time::{Duration, Timer};
+use iot_device::mdns::{Destination, Responder, MDNS_PORT};
+
+use super::{server::HTTP_PORT, Client, Connected};
+use crate::{log_debug, log_info, log_warn};
+
+const MDNS_ADDRESS: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+/// The ethernet address frames sent to [`MDNS_ADDRESS`] are delivered to.
+const MDNS_MAC_ADDRESS: [u8; 6] = [0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb];
+
+const PACKET_SIZE: usize = 512;
+
+impl Client<Connected> {
+    /// Answer mDNS queries for `<hostname>.local` and advertise the http server as a `_http._tcp` service.
+    pub async fn advertise(&self) -> ! {
+        if let Err(error) = self
+            .state
+            .control
+            .lock()
+            .await
+            .add_multicast_address(MDNS_MAC_ADDRESS)
+            .await
+        {
+            log_warn!("Failed to add mDNS multicast address: {error:?}");
+        }
+        if let Err(error) = self.stack.join_multicast_group(MDNS_ADDRESS) {
+            log_warn!("Failed to join mDNS multicast group: {error:?}");
+        }
+
+        let mut rx_meta = [PacketMetadata::EMPTY; 4];
+        let mut rx_buffer = [0; PACKET_SIZE * 2];
+        let mut tx_meta = [PacketMetadata::EMPTY; 4];
+        let mut tx_buffer = [0; PACKET_SIZE * 2];
+        let mut socket = UdpSocket::new(
+            self.stack,
+            &mut rx_meta,
+            &mut rx_buffer,
+            &mut tx_meta,
+            &mut tx_buffer,
+        );
+        if let Err(error) = socket.bind(MDNS_PORT) {
+            // Nothing else is served over mDNS, so carry on without it rather than taking the connection down.
+            log_warn!("Failed to bind mDNS socket, not advertising: {error:?}");
+            core::future::pending::<()>().await;
+        }
+
+        let responder = Responder::new(
+            &self.hostname,
+            self.state.config.address.address(),
+            HTTP_PORT,
+        );
+        let multicast = IpEndpoint::new(MDNS_ADDRESS.into(), MDNS_PORT);
+        let mut packet = [0; PACKET_SIZE];
+        let mut response = [0; PACKET_SIZE];
+
+        log_info!("Advertising as {}.local", self.hostname);
+
+        // Announce twice in case the first is lost, as recommended by the RFC.
+        for _ in 0..2 {
+            if let Ok(length) = responder.announcement(&mut response) {
+                socket.send_to(&response[..length], multicast).await.ok();
+            }
+            Timer::after(Duration::from_secs(1)).await;
+        }
+
+        loop {
+            let Ok((length, metadata)) = socket.recv_from(&mut packet).await else {
+                continue;
+            };
+
+            match responder.respond(&packet[..length], metadata.endpoint.port, &mut response) {
+                Ok(Some((length, destination))) => {
+                    let endpoint = match destination {
+                        Destination::Unicast => metadata.endpoint,
+                        Destination::Multicast => multicast,
+                    };
+                    if let Err(error) = socket.send_to(&response[..length], endpoint).await {
+                        log_warn!("Failed to send mDNS response: {error:?}");
+                    }
+                }
+                Ok(None) => {}
+                Err(error) => log_debug!("Ignoring malformed mDNS packet: {error}"),
+            }
+        }
+    }
+}
@@ -1,12 +1,261 @@
-use embassy_net::{Ipv4Address, StaticConfigV4};
+use core::ops::RangeInclusive;
+
+use embassy_net::{DhcpConfig, Ipv4Address, StaticConfigV4};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::Duration;
 use heapless::{String, Vec};
+use serde::{Deserialize, Serialize};
+use smoltcp::socket::dhcpv4::RetryConfig;
+use thiserror_no_std::Error;
+
+use super::{
+    display_to_string,
+    power::{PowerProfile, DEFAULT_UPLOAD_INTERVAL},
+    MAX_HOSTNAME_LEN,
+};
+use crate::{
+    log_warn, print, println,
+    serial::read_line,
+    storage::{self, Slot, StorageError},
+};
+
+/// A replacement config submitted while connected, see [`NetworkConfig::submit`].
+static CONFIG_UPDATE: Signal<CriticalSectionRawMutex, NetworkConfig> = Signal::new();
+
+const STORAGE_BUFFER_SIZE: usize = 512;
 
-use crate::{print, println, serial::read_line};
+/// Up to the 30 days [`crate::system::sleep`] can sleep for.
+const UPLOAD_INTERVAL_SECS: RangeInclusive<u64> = 1..=30 * 24 * 60 * 60;
+/// DHCP lease times are 32-bit seconds.
+const MAX_LEASE_SECS: RangeInclusive<u64> = 1..=u32::MAX as u64;
+const DISCOVER_TIMEOUT_SECS: RangeInclusive<u64> = 1..=60 * 60;
 
+#[derive(Clone)]
 pub struct NetworkConfig {
     pub ssid: String<32>,
     pub password: Option<String<64>>,
     pub ip_config: Option<StaticConfigV4>,
+    pub dhcp: DhcpSettings,
+    pub power: PowerProfile,
+    /// How long to wait between uploads when the power profile is duty cycled.
+    pub upload_interval: Duration,
+}
+
+/// Settings used when the address is acquired through DHCP.
+///
+/// The client identifier is always the MAC address, as embassy-net does not allow it to be changed.
+#[derive(Clone)]
+pub struct DhcpSettings {
+    /// The hostname sent to the DHCP server, which defaults to one derived from the MAC address.
+    pub hostname: Option<String<MAX_HOSTNAME_LEN>>,
+    /// Caps the lease duration offered by the server.
+    pub max_lease_duration: Option<Duration>,
+    /// How long to wait for an offer before sending another discover.
+    pub discover_timeout: Duration,
+    /// How many times a request is retried before starting over with a discover.
+    pub request_retries: u16,
+    /// Keep using an address after the server refuses to renew it.
+    pub ignore_naks: bool,
+}
+
+impl Default for DhcpSettings {
+    fn default() -> Self {
+        let retry_config = RetryConfig::default();
+        Self {
+            hostname: None,
+            max_lease_duration: None,
+            discover_timeout: Duration::from_secs(retry_config.discover_timeout.secs()),
+            request_retries: retry_config.request_retries,
+            ignore_naks: false,
+        }
+    }
+}
+
+impl DhcpSettings {
+    pub fn to_dhcp_config(&self, hostname: &str) -> DhcpConfig {
+        let mut config = DhcpConfig::default();
+        config.hostname = hostname.try_into().ok();
+        config.max_lease_duration = self.max_lease_duration;
+        config.retry_config.discover_timeout =
+            smoltcp::time::Duration::from_secs(self.discover_timeout.as_secs());
+        config.retry_config.request_retries = self.request_retries;
+        config.ignore_naks = self.ignore_naks;
+        config
+    }
+}
+
+#[derive(Clone, Copy, Debug, Error)]
+pub enum ConfigError {
+    #[error("Invalid value for `{0}`")]
+    InvalidField(&'static str),
+}
+
+/// The json representation of a [`NetworkConfig`], used by the http server and for storage.
+#[derive(Serialize, Deserialize)]
+pub struct ConfigDocument {
+    pub ssid: String<32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String<64>>,
+    #[serde(default)]
+    pub address: Option<String<18>>,
+    #[serde(default)]
+    pub gateway: Option<String<15>>,
+    #[serde(default)]
+    pub dns_servers: Vec<String<15>, 3>,
+    #[serde(default)]
+    pub hostname: Option<String<MAX_HOSTNAME_LEN>>,
+    #[serde(default)]
+    pub max_lease_secs: Option<u64>,
+    #[serde(default)]
+    pub discover_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub request_retries: Option<u16>,
+    #[serde(default)]
+    pub ignore_naks: bool,
+    #[serde(default)]
+    pub power: PowerProfile,
+    #[serde(default)]
+    pub upload_interval_secs: Option<u64>,
+}
+
+impl From<&NetworkConfig> for ConfigDocument {
+    fn from(config: &NetworkConfig) -> Self {
+        let ip_config = config.ip_config.as_ref();
+        Self {
+            ssid: config.ssid.clone(),
+            password: config.password.clone(),
+            address: ip_config.and_then(|ip_config| display_to_string(ip_config.address)),
+            gateway: ip_config
+                .and_then(|ip_config| ip_config.gateway)
+                .and_then(display_to_string),
+            dns_servers: ip_config
+                .map(|ip_config| {
+                    ip_config
+                        .dns_servers
+                        .iter()
+                        .filter_map(display_to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            hostname: config.dhcp.hostname.clone(),
+            max_lease_secs: config
+                .dhcp
+                .max_lease_duration
+                .map(|duration| duration.as_secs()),
+            discover_timeout_secs: Some(config.dhcp.discover_timeout.as_secs()),
+            request_retries: Some(config.dhcp.request_retries),
+            ignore_naks: config.dhcp.ignore_naks,
+            power: config.power,
+            upload_interval_secs: Some(config.upload_interval.as_secs()),
+        }
+    }
+}
+
+impl TryFrom<ConfigDocument> for NetworkConfig {
+    type Error = ConfigError;
+
+    fn try_from(document: ConfigDocument) -> Result<Self, Self::Error> {
+        if document.ssid.trim().is_empty() {
+            return Err(ConfigError::InvalidField("ssid"));
+        }
+
+        let password = document.password.filter(|password| !password.is_empty());
+        if password.as_ref().is_some_and(|password| password.len() < 8) {
+            return Err(ConfigError::InvalidField("password"));
+        }
+
+        let ip_config = document
+            .address
+            .map(|address| {
+                let address = address
+                    .parse()
+                    .map_err(|()| ConfigError::InvalidField("address"))?;
+                let gateway = document
+                    .gateway
+                    .map(|gateway| {
+                        gateway
+                            .parse()
+                            .map_err(|_| ConfigError::InvalidField("gateway"))
+                    })
+                    .transpose()?;
+                let dns_servers = document
+                    .dns_servers
+                    .iter()
+                    .map(|server| {
+                        server
+                            .parse()
+                            .map_err(|_| ConfigError::InvalidField("dns_servers"))
+                    })
+                    .collect::<Result<_, _>>()?;
+
+                Ok(StaticConfigV4 {
+                    address,
+                    gateway,
+                    dns_servers,
+                })
+            })
+            .transpose()?;
+
+        if document
+            .hostname
+            .as_ref()
+            .is_some_and(|hostname| !is_valid_hostname(hostname))
+        {
+            return Err(ConfigError::InvalidField("hostname"));
+        }
+
+        let defaults = DhcpSettings::default();
+        let dhcp = DhcpSettings {
+            hostname: document.hostname,
+            max_lease_duration: document
+                .max_lease_secs
+                .map(|secs| secs_within("max_lease_secs", secs, MAX_LEASE_SECS))
+                .transpose()?,
+            discover_timeout: document
+                .discover_timeout_secs
+                .map(|secs| secs_within("discover_timeout_secs", secs, DISCOVER_TIMEOUT_SECS))
+                .transpose()?
+                .unwrap_or(defaults.discover_timeout),
+            request_retries: document.request_retries.unwrap_or(defaults.request_retries),
+            ignore_naks: document.ignore_naks,
+        };
+
+        Ok(Self {
+            ssid: document.ssid,
+            password,
+            ip_config,
+            dhcp,
+            power: document.power,
+            upload_interval: document
+                .upload_interval_secs
+                .map(|secs| secs_within("upload_interval_secs", secs, UPLOAD_INTERVAL_SECS))
+                .transpose()?
+                .unwrap_or(DEFAULT_UPLOAD_INTERVAL),
+        })
+    }
+}
+
+/// `secs` as a [`Duration`], if it is within `range`.
+fn secs_within(
+    field: &'static str,
+    secs: u64,
+    range: RangeInclusive<u64>,
+) -> Result<Duration, ConfigError> {
+    if range.contains(&secs) {
+        Ok(Duration::from_secs(secs))
+    } else {
+        Err(ConfigError::InvalidField(field))
+    }
+}
+
+/// Whether `hostname` is a valid DNS label, as required for DHCP and mDNS.
+pub fn is_valid_hostname(hostname: &str) -> bool {
+    (1..=MAX_HOSTNAME_LEN).contains(&hostname.len())
+        && !hostname.starts_with('-')
+        && !hostname.ends_with('-')
+        && hostname
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || char == '-')
 }
 
 impl NetworkConfig {
@@ -44,14 +293,91 @@ impl NetworkConfig {
             }
         };
 
+        let dhcp = if ip_config.is_none() {
+            DhcpSettings::generate().await
+        } else {
+            DhcpSettings::default()
+        };
+
+        let power = loop {
+            print!("Enter power profile [");
+            for (index, profile) in PowerProfile::ALL.into_iter().enumerate() {
+                if index > 0 {
+                    print!("/");
+                }
+                print!("{}", profile.name());
+            }
+            print!("] (leave blank for {}): ", PowerProfile::default().name());
+            let mut buf = String::<16>::new();
+            read_line(&mut buf).await.ok();
+            if buf.trim().is_empty() {
+                break PowerProfile::default();
+            }
+            if let Some(profile) = PowerProfile::ALL
+                .into_iter()
+                .find(|profile| profile.name() == buf.trim())
+            {
+                break profile;
+            }
+            println!("Unknown power profile `{}`", buf.trim());
+        };
+
+        let upload_interval = if power.is_duty_cycled() {
+            read_secs(
+                "Enter seconds between uploads (leave blank for default): ",
+                UPLOAD_INTERVAL_SECS,
+            )
+            .await
+            .unwrap_or(DEFAULT_UPLOAD_INTERVAL)
+        } else {
+            DEFAULT_UPLOAD_INTERVAL
+        };
+
         // Trim newlines off of strings
         Self {
             ssid: ssid.trim().try_into().unwrap(),
             password: password.map(|p| p.trim().try_into().unwrap()),
             ip_config,
+            dhcp,
+            power,
+            upload_interval,
         }
     }
 
+    /// Load the config saved by [`NetworkConfig::save`], if there is a valid one.
+    pub async fn load() -> Option<Self> {
+        let mut buffer = [0; STORAGE_BUFFER_SIZE];
+        let data = match storage::load(Slot::NetworkConfig, &mut buffer).await {
+            Ok(data) => data,
+            Err(StorageError::Empty) => return None,
+            Err(error) => {
+                log_warn!("Failed to load network config: {error}");
+                return None;
+            }
+        };
+
+        let (document, _) = serde_json_core::from_slice::<ConfigDocument>(data).ok()?;
+        document.try_into().ok()
+    }
+
+    /// Persist the config to flash so it is used on the next boot.
+    pub async fn save(&self) -> Result<(), StorageError> {
+        let mut buffer = [0; STORAGE_BUFFER_SIZE];
+        let length = serde_json_core::to_slice(&ConfigDocument::from(self), &mut buffer)
+            .map_err(|_| StorageError::TooLarge)?;
+        storage::store(Slot::NetworkConfig, &buffer[..length]).await
+    }
+
+    /// Replace the config of the running device, causing it to reconnect.
+    pub fn submit(self) {
+        CONFIG_UPDATE.signal(self);
+    }
+
+    /// Wait for a new config to be submitted through [`NetworkConfig::submit`].
+    pub async fn wait_for_update() -> Self {
+        CONFIG_UPDATE.wait().await
+    }
+
     async fn create_static_config() -> StaticConfigV4 {
         let address = loop {
             print!("Enter device address with subnet [eg: `192.128.1.1/24`]: ");
@@ -104,3 +430,91 @@ impl NetworkConfig {
         }
     }
 }
+
+impl DhcpSettings {
+    async fn generate() -> Self {
+        let hostname = loop {
+            print!("Enter hostname (leave blank for default): ");
+            let mut buf = String::<64>::new();
+            read_line(&mut buf).await.ok();
+            let hostname = buf.trim();
+            if hostname.is_empty() {
+                break None;
+            }
+            if is_valid_hostname(hostname) {
+                break hostname.try_into().ok();
+            }
+            println!("Hostname must be up to {MAX_HOSTNAME_LEN} letters, digits or hyphens");
+        };
+
+        let mut settings = Self {
+            hostname,
+            ..Self::default()
+        };
+
+        loop {
+            let mut choice = String::<2>::new();
+            print!("Configure DHCP lease and retries? [y/N] ");
+            read_line(&mut choice).await.ok();
+            match choice.trim().chars().next() {
+                Some('n') | None => return settings,
+                Some('y') => break,
+                _ => {}
+            }
+        }
+
+        settings.max_lease_duration = read_secs(
+            "Enter maximum lease duration in seconds (leave blank for none): ",
+            MAX_LEASE_SECS,
+        )
+        .await;
+
+        if let Some(timeout) = read_secs(
+            "Enter DHCP discover timeout in seconds (leave blank for default): ",
+            DISCOVER_TIMEOUT_SECS,
+        )
+        .await
+        {
+            settings.discover_timeout = timeout;
+        }
+
+        if let Some(retries) =
+            read_number("Enter DHCP request retries (leave blank for default): ").await
+        {
+            settings.request_retries = retries;
+        }
+
+        settings
+    }
+}
+
+/// Prompt for a number of seconds until one within `range` or a blank line is entered.
+async fn read_secs(prompt: &str, range: RangeInclusive<u64>) -> Option<Duration> {
+    loop {
+        let secs = read_number(prompt).await?;
+        if range.contains(&secs) {
+            return Some(Duration::from_secs(secs));
+        }
+        println!(
+            "Must be between {} and {} seconds",
+            range.start(),
+            range.end()
+        );
+    }
+}
+
+/// Prompt for a number until a valid one or a blank line is entered.
+async fn read_number<T: core::str::FromStr>(prompt: &str) -> Option<T> {
+    loop {
+        print!("{prompt}");
+        let mut buf = String::<32>::new();
+        read_line(&mut buf).await.ok();
+        if buf.trim().is_empty() {
+            return None;
+        }
+        match buf.trim().parse() {
+            Ok(number) => return Some(number),
+            Err(_) => println!("Incorrect number inputted"),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Power management profiles for the radio.
+
+use cyw43::PowerManagementMode;
+use embassy_time::Duration;
+use serde::{Deserialize, Serialize};
+
+use super::{Client, Connected, Disconnected};
+
+/// How long to wait between uploads with [`PowerProfile::BatterySaver`] or [`PowerProfile::DeepSleep`], unless the
+/// config says otherwise.
+pub const DEFAULT_UPLOAD_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Trades the radio's latency against its power draw.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerProfile {
+    /// For mains powered devices where latency matters.
+    Performance,
+    #[default]
+    PowerSave,
+    SuperSave,
+    /// [`PowerProfile::SuperSave`], and the device leaves the network between uploads. The radio stays powered, only
+    /// [`PowerProfile::DeepSleep`] turns it off.
+    BatterySaver,
+    /// Upload, then power the radio down and sleep until the next upload, see [`crate::system::sleep`].
+    DeepSleep,
+}
+
+impl PowerProfile {
+    pub const ALL: [Self; 5] = [
+        Self::Performance,
+        Self::PowerSave,
+        Self::SuperSave,
+        Self::BatterySaver,
+        Self::DeepSleep,
+    ];
+
+    /// Must match the names used by serde.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Performance => "performance",
+            Self::PowerSave => "power_save",
+            Self::SuperSave => "super_save",
+            Self::BatterySaver => "battery_saver",
+            Self::DeepSleep => "deep_sleep",
+        }
+    }
+
+    /// Whether the device only wakes up to upload.
+    pub const fn is_duty_cycled(self) -> bool {
+        matches!(self, Self::BatterySaver | Self::DeepSleep)
+    }
+
+    const fn mode(self) -> PowerManagementMode {
+        match self {
+            Self::Performance => PowerManagementMode::Performance,
+            Self::PowerSave => PowerManagementMode::PowerSave,
+            Self::SuperSave | Self::BatterySaver | Self::DeepSleep => {
+                PowerManagementMode::SuperSave
+            }
+        }
+    }
+}
+
+impl<T> Client<T> {
+    /// The profile last set, which is kept across connecting and disconnecting.
+    pub const fn power_profile(&self) -> PowerProfile {
+        self.power.get()
+    }
+}
+
+impl Client<Disconnected> {
+    pub async fn set_power_profile(&mut self, profile: PowerProfile) {
+        self.state
+            .control
+            .set_power_management(profile.mode())
+            .await;
+        self.power.set(profile);
+    }
+}
+
+impl Client<Connected> {
+    pub async fn set_power_profile(&self, profile: PowerProfile) {
+        self.state
+            .control
+            .lock()
+            .await
+            .set_power_management(profile.mode())
+            .await;
+        self.power.set(profile);
+    }
+}
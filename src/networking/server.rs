@@ -0,0 +1,397 @@
+use core::fmt::{self, Write as _};
+
+#[cfg(feature = "usb-ethernet")]
+use embassy_futures::select::{select, Either};
+use embassy_net::{tcp, tcp::TcpSocket, Ipv4Address, Stack};
+use embassy_time::{Duration, Instant};
+use embedded_io_async::Write;
+use heapless::{String, Vec};
+use iot_device::http::{self, ParseError, Request, Status};
+use iot_device::metrics;
+use reqwless::request::Method;
+use serde::Serialize;
+use serde_json_core::{de, ser};
+use thiserror_no_std::Error;
+
+use super::{
+    link::HISTORY_LEN,
+    network_config::{ConfigDocument, ConfigError, NetworkConfig},
+    Client, Connected,
+};
+#[cfg(feature = "alloc")]
+use crate::allocator;
+use crate::system::memory::{self, Usage};
+use crate::{log_debug, log_info, log_warn};
+
+pub const HTTP_PORT: u16 = 80;
+
+const SOCKET_BUFFER_SIZE: usize = 1024;
+const REQUEST_BUFFER_SIZE: usize = 1024;
+const RESPONSE_BUFFER_SIZE: usize = metrics::MAX_RENDER_SIZE;
+
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Handles a request that matched a [`Route`], writing the result into the [`Response`].
+pub type Handler = fn(&Request<'_>, &Client<Connected>, &mut Response) -> Result<(), ServerError>;
+
+pub struct Route {
+    pub method: Method,
+    pub path: &'static str,
+    pub handler: Handler,
+}
+
+/// The endpoints built into the device.
+pub const ROUTES: &[Route] = &[
+    Route {
+        method: Method::GET,
+        path: "/status",
+        handler: get_status,
+    },
+    Route {
+        method: Method::GET,
+        path: "/config",
+        handler: get_config,
+    },
+    Route {
+        method: Method::POST,
+        path: "/config",
+        handler: post_config,
+    },
+    Route {
+        method: Method::GET,
+        path: "/metrics",
+        handler: get_metrics,
+    },
+    Route {
+        method: Method::GET,
+        path: "/link",
+        handler: get_link,
+    },
+];
+
+#[derive(Error, Debug)]
+pub enum ServerError {
+    #[error("Failed to parse request: `{0}`")]
+    ParseError(#[from] ParseError),
+    #[error("Failed to decode request body: `{0}`")]
+    JsonDecodingError(#[from] de::Error),
+    #[error("Failed to encode response body: `{0}`")]
+    JsonEncodingError(#[from] ser::Error),
+    #[error("Invalid config: `{0}`")]
+    ConfigError(#[from] ConfigError),
+    #[error("Response body does not fit in the buffer")]
+    ResponseOverflow,
+    #[error("An error occured with the socket: `{0:?}`")]
+    SocketError(tcp::Error),
+}
+
+impl From<tcp::Error> for ServerError {
+    fn from(value: tcp::Error) -> Self {
+        Self::SocketError(value)
+    }
+}
+
+impl ServerError {
+    const fn status(&self) -> Status {
+        match self {
+            Self::ParseError(_) | Self::JsonDecodingError(_) | Self::ConfigError(_) => {
+                Status::BadRequest
+            }
+            Self::JsonEncodingError(_) | Self::ResponseOverflow | Self::SocketError(_) => {
+                Status::InternalServerError
+            }
+        }
+    }
+}
+
+pub struct Response {
+    status: Status,
+    content_type: &'static str,
+    body: Vec<u8, RESPONSE_BUFFER_SIZE>,
+}
+
+impl Response {
+    const fn new() -> Self {
+        Self {
+            status: Status::Ok,
+            content_type: "application/json",
+            body: Vec::new(),
+        }
+    }
+
+    pub const fn set_status(&mut self, status: Status) {
+        self.status = status;
+    }
+
+    pub const fn set_content_type(&mut self, content_type: &'static str) {
+        self.content_type = content_type;
+    }
+
+    /// Serialize `value` as the json response body.
+    pub fn json<T: Serialize>(&mut self, value: &T) -> Result<(), ServerError> {
+        self.content_type = "application/json";
+        self.body.clear();
+        self.body
+            .resize_default(RESPONSE_BUFFER_SIZE)
+            .map_err(|()| ServerError::ResponseOverflow)?;
+        let length = serde_json_core::to_slice(value, &mut self.body)?;
+        self.body.truncate(length);
+        Ok(())
+    }
+}
+
+/// Allows plain text bodies to be written with [`write!`].
+impl fmt::Write for Response {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.body
+            .extend_from_slice(s.as_bytes())
+            .map_err(|()| fmt::Error)
+    }
+}
+
+impl Client<Connected> {
+    /// Serve http requests on port 80, dispatching them to the matching route in `routes`.
+    ///
+    /// Connections are handled one at a time on each link and closed after each response.
+    pub async fn serve(&self, routes: &[Route]) -> ! {
+        #[cfg(feature = "usb-ethernet")]
+        if let Some(stack) = super::usb_ethernet::stack() {
+            match select(
+                self.serve_on(self.stack, routes),
+                self.serve_on(stack, routes),
+            )
+            .await
+            {
+                Either::First(never) | Either::Second(never) => match never {},
+            }
+        }
+
+        self.serve_on(self.stack, routes).await
+    }
+
+    async fn serve_on(&self, stack: Stack<'static>, routes: &[Route]) -> ! {
+        let mut rx_buffer = [0; SOCKET_BUFFER_SIZE];
+        let mut tx_buffer = [0; SOCKET_BUFFER_SIZE];
+        let mut request_buffer = [0; REQUEST_BUFFER_SIZE];
+
+        loop {
+            let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+            socket.set_timeout(Some(SOCKET_TIMEOUT));
+
+            if let Err(error) = socket.accept(HTTP_PORT).await {
+                log_warn!("Failed to accept connection: {error:?}");
+                continue;
+            }
+
+            if let Err(error) = self
+                .handle_connection(&mut socket, routes, &mut request_buffer)
+                .await
+            {
+                log_warn!("Failed to handle connection: {error:?}");
+            }
+
+            socket.close();
+            let _ = socket.flush().await;
+        }
+    }
+
+    async fn handle_connection(
+        &self,
+        socket: &mut TcpSocket<'_>,
+        routes: &[Route],
+        buffer: &mut [u8],
+    ) -> Result<(), tcp::Error> {
+        let mut length = 0;
+        loop {
+            if length == buffer.len() {
+                return send_error(socket, Status::PayloadTooLarge).await;
+            }
+
+            let read = socket.read(&mut buffer[length..]).await?;
+            if read == 0 {
+                // Connection closed before a full request was received.
+                return Ok(());
+            }
+            length += read;
+
+            if !matches!(
+                Request::parse(&buffer[..length]),
+                Err(ParseError::Incomplete)
+            ) {
+                break;
+            }
+        }
+
+        let request = match Request::parse(&buffer[..length]) {
+            Ok(request) => request,
+            Err(error) => {
+                log_debug!("Received malformed request: {error}");
+                return send_error(socket, Status::BadRequest).await;
+            }
+        };
+
+        log_info!("{:?} {}", request.method, request.path);
+
+        let mut response = Response::new();
+
+        match http::route(routes, &request, |route| (route.method, route.path)) {
+            Ok(route) => {
+                if let Err(error) = (route.handler)(&request, self, &mut response) {
+                    log_warn!("Failed to handle request: {error}");
+                    response = Response::new();
+                    response.set_status(error.status());
+                    write!(response, "{error}").ok();
+                    response.set_content_type("text/plain");
+                }
+            }
+            Err(status) => response.set_status(status),
+        }
+
+        send_response(socket, &response).await
+    }
+}
+
+async fn send_error(socket: &mut TcpSocket<'_>, status: Status) -> Result<(), tcp::Error> {
+    let mut response = Response::new();
+    response.set_status(status);
+    send_response(socket, &response).await
+}
+
+async fn send_response(socket: &mut TcpSocket<'_>, response: &Response) -> Result<(), tcp::Error> {
+    let mut head = String::<128>::new();
+    http::write_head(
+        &mut head,
+        response.status,
+        response.content_type,
+        response.body.len(),
+    )
+    .expect("Failed to write to header buffer");
+
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(&response.body).await?;
+    socket.flush().await
+}
+
+#[derive(Serialize)]
+struct StatusBody {
+    address: String<18>,
+    gateway: Option<String<15>>,
+    dns_servers: Vec<String<15>, 3>,
+    uptime: u64,
+    rssi: Option<i16>,
+    stack: Usage,
+    task_arena: Usage,
+    #[cfg(feature = "alloc")]
+    heap: metrics::HeapStats,
+}
+
+fn get_status(
+    _: &Request<'_>,
+    client: &Client<Connected>,
+    response: &mut Response,
+) -> Result<(), ServerError> {
+    let config = &client.state.config;
+
+    let mut address = String::new();
+    write!(address, "{}", config.address).map_err(|_| ServerError::ResponseOverflow)?;
+
+    let dns_servers = config
+        .dns_servers
+        .iter()
+        .copied()
+        .map(format_address)
+        .collect::<Result<_, _>>()?;
+
+    response.json(&StatusBody {
+        address,
+        gateway: config
+            .gateway
+            .as_ref()
+            .copied()
+            .map(format_address)
+            .transpose()?,
+        dns_servers,
+        uptime: Instant::now().as_secs(),
+        rssi: client.rssi(),
+        stack: memory::stack(),
+        task_arena: memory::task_arena(),
+        #[cfg(feature = "alloc")]
+        heap: allocator::stats(),
+    })
+}
+
+fn get_config(
+    _: &Request<'_>,
+    client: &Client<Connected>,
+    response: &mut Response,
+) -> Result<(), ServerError> {
+    let mut document = ConfigDocument::from(&client.state.network_config);
+    document.password = None;
+    response.json(&document)
+}
+
+/// Replace the network config, the device will reconnect using it once the response is sent.
+fn post_config(
+    request: &Request<'_>,
+    _: &Client<Connected>,
+    response: &mut Response,
+) -> Result<(), ServerError> {
+    let document: ConfigDocument = request.json()?;
+    NetworkConfig::try_from(document)?.submit();
+
+    response.set_status(Status::Accepted);
+    Ok(())
+}
+
+fn get_metrics(
+    _: &Request<'_>,
+    _: &Client<Connected>,
+    response: &mut Response,
+) -> Result<(), ServerError> {
+    response.set_content_type("text/plain; version=0.0.4");
+    #[cfg(feature = "alloc")]
+    let heap = Some(allocator::stats());
+    #[cfg(not(feature = "alloc"))]
+    let heap = None;
+    metrics::render(response, Instant::now().as_secs(), heap.as_ref())
+        .map_err(|_| ServerError::ResponseOverflow)
+}
+
+#[derive(Serialize)]
+struct LinkBody {
+    uptime: u64,
+    rssi: i16,
+    channel: u8,
+    bssid: String<17>,
+    max_rate: u32,
+}
+
+/// The link samples taken since joining the network, oldest first.
+fn get_link(
+    _: &Request<'_>,
+    client: &Client<Connected>,
+    response: &mut Response,
+) -> Result<(), ServerError> {
+    let history = client
+        .link_history()
+        .into_iter()
+        .map(|sample| -> Result<_, ServerError> {
+            let mut bssid = String::new();
+            write!(bssid, "{}", sample.bssid).map_err(|_| ServerError::ResponseOverflow)?;
+            Ok(LinkBody {
+                uptime: sample.uptime,
+                rssi: sample.rssi,
+                channel: sample.channel,
+                bssid,
+                max_rate: sample.max_rate,
+            })
+        })
+        .collect::<Result<Vec<_, HISTORY_LEN>, _>>()?;
+    response.json(&history)
+}
+
+fn format_address(address: Ipv4Address) -> Result<String<15>, ServerError> {
+    let mut buf = String::new();
+    write!(buf, "{address}").map_err(|_| ServerError::ResponseOverflow)?;
+    Ok(buf)
+}
@@ -0,0 +1,83 @@
+//! Wrappers around the I/O the wireless and network runners loop on, which check in with the watchdog each time
+//! round the runner's loop.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::Context;
+
+use cyw43::SpiBusCyw43;
+use embassy_net::driver::{Capabilities, Driver, HardwareAddress, LinkState};
+use embassy_time::Timer;
+
+use crate::system::watchdog::{self, Task, CHECK_IN_INTERVAL};
+
+/// The bus to the CYW43, the runner waits for events from it at the top of its loop.
+pub struct SupervisedSpi<S>(pub S);
+
+impl<S: SpiBusCyw43> SpiBusCyw43 for SupervisedSpi<S> {
+    async fn cmd_write(&mut self, write: &[u32]) -> u32 {
+        self.0.cmd_write(write).await
+    }
+
+    async fn cmd_read(&mut self, write: u32, read: &mut [u32]) -> u32 {
+        self.0.cmd_read(write, read).await
+    }
+
+    /// Returns early after [`CHECK_IN_INTERVAL`], the runner then finds there's nothing to do and waits again.
+    async fn wait_for_event(&mut self) {
+        watchdog::check_in_within(Task::Cyw43, self.0.wait_for_event()).await;
+    }
+}
+
+/// The driver under the network stack, which polls it every time it runs.
+pub struct SupervisedDriver<D> {
+    inner: D,
+    /// Wakes the stack so it runs at least every [`CHECK_IN_INTERVAL`].
+    timer: Timer,
+}
+
+impl<D> SupervisedDriver<D> {
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            timer: Timer::after(CHECK_IN_INTERVAL),
+        }
+    }
+}
+
+impl<D: Driver> Driver for SupervisedDriver<D> {
+    type RxToken<'a>
+        = D::RxToken<'a>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = D::TxToken<'a>
+    where
+        Self: 'a;
+
+    fn receive(&mut self, cx: &mut Context<'_>) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        self.inner.receive(cx)
+    }
+
+    fn transmit(&mut self, cx: &mut Context<'_>) -> Option<Self::TxToken<'_>> {
+        self.inner.transmit(cx)
+    }
+
+    /// Checked once each time the stack runs.
+    fn link_state(&mut self, cx: &mut Context<'_>) -> LinkState {
+        watchdog::check_in(Task::Net);
+        if Pin::new(&mut self.timer).poll(cx).is_ready() {
+            self.timer = Timer::after(CHECK_IN_INTERVAL);
+            let _ = Pin::new(&mut self.timer).poll(cx);
+        }
+        self.inner.link_state(cx)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn hardware_address(&self) -> HardwareAddress {
+        self.inner.hardware_address()
+    }
+}
@@ -0,0 +1,95 @@
+//! A USB network adapter (CDC-NCM) alongside the serial ports, so the device is reachable over the cable alone.
+//!
+//! The device takes [`ADDRESS`] on its own network stack, the host should give its end of the link [`GATEWAY`].
+//! The http server answers on both links, and requests can be sent over this one with [`set_preferred`] if the
+//! host routes them on, eg: with `ip addr add 10.42.0.1/24 dev usb0` and NAT.
+
+use embassy_futures::join::join;
+use embassy_net::{Config, Ipv4Address, Ipv4Cidr, Stack, StackResources, StaticConfigV4};
+use embassy_rp::clocks::RoscRng;
+use embassy_sync::once_lock::OnceLock;
+use embassy_usb::{
+    class::cdc_ncm::{self, embassy_net::Device, CdcNcmClass},
+    Builder,
+};
+use heapless::Vec;
+use portable_atomic::{AtomicBool, Ordering};
+use rand::RngCore;
+use static_cell::StaticCell;
+
+use crate::serial::UsbDriver;
+
+const MTU: usize = 1514;
+
+/// Locally administered addresses for each end of the link.
+const HOST_MAC_ADDRESS: [u8; 6] = [0x02, 0x49, 0x4f, 0x54, 0x00, 0x01];
+const DEVICE_MAC_ADDRESS: [u8; 6] = [0x02, 0x49, 0x4f, 0x54, 0x00, 0x02];
+
+const ADDRESS: Ipv4Cidr = Ipv4Cidr::new(Ipv4Address::new(10, 42, 0, 2), 24);
+const GATEWAY: Ipv4Address = Ipv4Address::new(10, 42, 0, 1);
+
+static STACK: OnceLock<Stack<'static>> = OnceLock::new();
+
+static PREFERRED: AtomicBool = AtomicBool::new(false);
+
+/// The network stack on the USB link, once [`add`] has run.
+pub fn stack() -> Option<Stack<'static>> {
+    STACK.try_get().copied()
+}
+
+/// Whether requests are sent over the USB link rather than Wi-Fi.
+pub fn preferred() -> bool {
+    PREFERRED.load(Ordering::Relaxed)
+}
+
+pub fn set_preferred(preferred: bool) {
+    PREFERRED.store(preferred, Ordering::Relaxed);
+}
+
+/// Runs the USB link, see [`add`].
+pub struct UsbEthernet {
+    class: cdc_ncm::embassy_net::Runner<'static, UsbDriver, MTU>,
+    stack: embassy_net::Runner<'static, Device<'static, MTU>>,
+}
+
+/// Add the network adapter to the USB device and bring up its network stack.
+pub fn add(builder: &mut Builder<'static, UsbDriver>) -> UsbEthernet {
+    static CLASS_STATE: StaticCell<cdc_ncm::State> = StaticCell::new();
+    static DEVICE_STATE: StaticCell<cdc_ncm::embassy_net::State<MTU, 4, 4>> = StaticCell::new();
+    static RESOURCES: StaticCell<StackResources<4>> = StaticCell::new();
+
+    let class = CdcNcmClass::new(
+        builder,
+        CLASS_STATE.init(cdc_ncm::State::new()),
+        HOST_MAC_ADDRESS,
+        64,
+    );
+    let (class, device) = class.into_embassy_net_device(
+        DEVICE_STATE.init(cdc_ncm::embassy_net::State::new()),
+        DEVICE_MAC_ADDRESS,
+    );
+
+    let config = Config::ipv4_static(StaticConfigV4 {
+        address: ADDRESS,
+        gateway: Some(GATEWAY),
+        dns_servers: Vec::from_slice(&[GATEWAY]).unwrap_or_default(),
+    });
+    let (stack, runner) = embassy_net::new(
+        device,
+        config,
+        RESOURCES.init(StackResources::new()),
+        RoscRng.next_u64(),
+    );
+    STACK.init(stack).ok();
+
+    UsbEthernet {
+        class,
+        stack: runner,
+    }
+}
+
+impl UsbEthernet {
+    pub async fn run(mut self) -> ! {
+        join(self.class.run(), self.stack.run()).await.0
+    }
+}
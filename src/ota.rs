@@ -0,0 +1,167 @@
+//! Over-the-air firmware updates.
+//!
+//! Images are written to the `DFU` region reserved in `memory.x` and swapped into place on the next boot by the
+//! bootloader in `bootloader/`. A swapped in image is on trial until [`confirm`] is called, if the device resets
+//! before then the bootloader reverts to the previous image.
+//!
+//! Images must be signed with the ed25519 key whose public half is given in `OTA_PUBLIC_KEY` at build time,
+//! the signature is over the SHA-512 digest of the image.
+//!
+//! The application no longer starts on its own, so the bootloader must be flashed once by running
+//! `cargo run --release` in `bootloader/`.
+
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use embassy_boot::FirmwareUpdaterError;
+use embassy_boot_rp::{AlignedBuffer, BlockingFirmwareUpdater, FirmwareUpdaterConfig, State};
+use embassy_embedded_hal::flash::partition::BlockingPartition;
+use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
+use embassy_time::{Duration, Timer};
+use heapless::{String, Vec};
+use portable_atomic::{AtomicBool, Ordering};
+use thiserror_no_std::Error;
+
+use crate::{
+    log_info,
+    networking::{Client, Connected, RequestError},
+    println,
+    storage::{self, FlashDevice, StorageError},
+    system,
+};
+
+/// Offsets of the `BOOTLOADER_STATE` and `DFU` regions from the start of flash, must match `memory.x`.
+const STATE_OFFSET: u32 = 0x6000;
+const STATE_SIZE: u32 = 4 * 1024;
+const DFU_OFFSET: u32 = 0xE_1000;
+const DFU_SIZE: u32 = 876 * 1024;
+
+/// How long a new image has to call [`confirm`] before it is rolled back.
+const TRIAL_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+const SIGNATURE_SIZE: usize = 64;
+
+include!(concat!(env!("OUT_DIR"), "/ota_public_key.rs"));
+
+static CONFIRMED: AtomicBool = AtomicBool::new(false);
+
+type Partition<'a, 'd> = BlockingPartition<'a, NoopRawMutex, &'d mut FlashDevice>;
+type SharedFlash<'d> = Mutex<NoopRawMutex, RefCell<&'d mut FlashDevice>>;
+
+#[derive(Debug, Error)]
+pub enum UpdateError {
+    #[error("No public key was provided in `OTA_PUBLIC_KEY` at build time")]
+    NoPublicKey,
+    #[error("Url is too long")]
+    UrlTooLong,
+    #[error("Signature must be {SIGNATURE_SIZE} bytes")]
+    BadSignature,
+    #[error("Failed to download the update: `{0}`")]
+    RequestError(#[from] RequestError),
+    #[error("Failed to access flash: `{0}`")]
+    StorageError(#[from] StorageError),
+    #[error("Failed to write or verify the update: `{0:?}`")]
+    UpdaterError(FirmwareUpdaterError),
+}
+
+impl From<FirmwareUpdaterError> for UpdateError {
+    fn from(value: FirmwareUpdaterError) -> Self {
+        Self::UpdaterError(value)
+    }
+}
+
+fn updater<'a, 'd>(
+    flash: &'a SharedFlash<'d>,
+    aligned: &'a mut [u8],
+) -> BlockingFirmwareUpdater<'a, Partition<'a, 'd>, Partition<'a, 'd>> {
+    let config = FirmwareUpdaterConfig {
+        dfu: BlockingPartition::new(flash, DFU_OFFSET, DFU_SIZE),
+        state: BlockingPartition::new(flash, STATE_OFFSET, STATE_SIZE),
+    };
+    BlockingFirmwareUpdater::new(config, aligned)
+}
+
+/// Download the image at `url` and its signature at `{url}.sig`, then mark it to be swapped in on the next boot.
+///
+/// Flash is held for the whole download, so records can't be stored until it finishes.
+#[allow(clippy::significant_drop_tightening)]
+pub async fn update(client: &Client<Connected>, url: &str) -> Result<(), UpdateError> {
+    let public_key = PUBLIC_KEY.ok_or(UpdateError::NoPublicKey)?;
+
+    let mut signature_url = String::<256>::new();
+    write!(signature_url, "{url}.sig").map_err(|_| UpdateError::UrlTooLong)?;
+    let mut signature = Vec::<u8, SIGNATURE_SIZE>::new();
+    client
+        .download(&signature_url, |chunk| {
+            signature
+                .extend_from_slice(chunk)
+                .map_err(|()| UpdateError::BadSignature)
+        })
+        .await?;
+    let signature: [u8; SIGNATURE_SIZE] = signature
+        .into_array()
+        .map_err(|_| UpdateError::BadSignature)?;
+
+    let mut flash = storage::lock().await?;
+    let flash = Mutex::new(RefCell::new(&mut *flash));
+    let mut aligned = AlignedBuffer([0; 1]);
+    let mut updater = updater(&flash, &mut aligned.0);
+
+    let mut size = 0;
+    client
+        .download(url, |chunk| {
+            updater.write_firmware(size, chunk)?;
+            size += chunk.len();
+            Ok::<_, UpdateError>(())
+        })
+        .await?;
+
+    let size = u32::try_from(size).map_err(|_| FirmwareUpdaterError::BadState)?;
+    updater.verify_and_mark_updated(&public_key, &signature, size)?;
+
+    Ok(())
+}
+
+/// Mark the running image as healthy, so it isn't rolled back on the next reset.
+#[allow(clippy::significant_drop_tightening)]
+pub async fn confirm() -> Result<(), UpdateError> {
+    if CONFIRMED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let mut flash = storage::lock().await?;
+    let flash = Mutex::new(RefCell::new(&mut *flash));
+    let mut aligned = AlignedBuffer([0; 1]);
+    let mut updater = updater(&flash, &mut aligned.0);
+
+    if updater.get_state()? == State::Swap {
+        updater.mark_booted()?;
+        log_info!("Update confirmed");
+    }
+
+    CONFIRMED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+#[allow(clippy::significant_drop_tightening)]
+async fn state() -> Result<State, UpdateError> {
+    let mut flash = storage::lock().await?;
+    let flash = Mutex::new(RefCell::new(&mut *flash));
+    let mut aligned = AlignedBuffer([0; 1]);
+    Ok(updater(&flash, &mut aligned.0).get_state()?)
+}
+
+/// Reset the device if a newly swapped in image isn't confirmed within [`TRIAL_PERIOD`], so that it's rolled back.
+#[embassy_executor::task]
+pub async fn watch_trial() {
+    if !matches!(state().await, Ok(State::Swap)) {
+        return;
+    }
+
+    Timer::after(TRIAL_PERIOD).await;
+
+    if !CONFIRMED.load(Ordering::Relaxed) {
+        println!("Update was not confirmed, rolling back");
+        system::reboot().await;
+    }
+}
@@ -1,48 +1,143 @@
+pub mod log;
+pub mod port;
 pub mod print;
+mod supervised;
 
 use core::fmt::{self, Write};
-use core::sync::atomic::Ordering;
 
-use defmt::info;
-use embassy_futures::join::join;
+use defmt_rtt as _;
+use embassy_futures::join::join4;
 use embassy_futures::select::{select, Either};
-use embassy_futures::yield_now;
 use embassy_rp::bind_interrupts;
 use embassy_rp::peripherals::USB;
-use embassy_rp::usb::{Driver, Instance, InterruptHandler};
+use embassy_rp::usb::{Driver, InterruptHandler};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
+use embassy_sync::pipe::Pipe;
+use embassy_sync::signal::Signal;
+use embassy_sync::watch::{self, Watch};
 use embassy_time::Timer;
-use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::class::cdc_acm::{CdcAcmClass, ControlChanged, Receiver, Sender, State};
 use embassy_usb::driver::EndpointError;
 use embassy_usb::{Builder, Config};
-use heapless::{Deque, Vec};
-use portable_atomic::AtomicBool;
-use {defmt_rtt as _, panic_probe as _};
+use embedded_io_async::Write as _;
+use heapless::String;
+use iot_device::metrics;
+use portable_atomic::{AtomicBool, Ordering};
+use static_cell::StaticCell;
+
+use crate::{log_info, log_warn, storage, system};
+use port::SerialPort;
+use supervised::SupervisedUsb;
 
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => InterruptHandler<USB>;
 });
 
+/// Opening then closing the console at this baud rate reboots into the USB mass storage bootloader.
+const BOOTLOADER_TOUCH_BAUD: u32 = 1200;
+
 const BUFFER_SIZE: usize = 1024;
+const PACKET_SIZE: usize = 64;
+
+/// How many tasks can subscribe to the connection state at once, waiters fall back to polling beyond that.
+const CONNECTION_WAITERS: usize = 4;
+
+/// The USB driver every class on the bus is built on.
+pub type UsbDriver = SupervisedUsb<Driver<'static, USB>>;
+
+static STD_IN: Pipe<CriticalSectionRawMutex, BUFFER_SIZE> = Pipe::new();
+static STD_OUT: Pipe<CriticalSectionRawMutex, BUFFER_SIZE> = Pipe::new();
+
+/// Signalled whenever [`STD_OUT`] has been emptied, see [`print::flush`].
+static STD_OUT_DRAINED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Makes sure only one task reads from [`STD_IN`] at a time, so lines aren't split between readers.
+static STD_IN_READ_LOCK: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+
+/// Set when a line ended with a carriage return, so a line feed straight after it is dropped.
+static AFTER_CR: AtomicBool = AtomicBool::new(false);
 
-static STD_IN: Mutex<CriticalSectionRawMutex, Deque<u8, BUFFER_SIZE>> = Mutex::new(Deque::new());
-static STD_OUT: Mutex<CriticalSectionRawMutex, Vec<u8, BUFFER_SIZE>> = Mutex::new(Vec::new());
+/// The latest [`ConnectionState`] of the serial port.
+static CONNECTION: Watch<CriticalSectionRawMutex, ConnectionState, CONNECTION_WAITERS> =
+    Watch::new();
+
+pub type ConnectionReceiver =
+    watch::Receiver<'static, CriticalSectionRawMutex, ConnectionState, CONNECTION_WAITERS>;
+
+/// Whether a host is talking to the serial port.
+#[derive(Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct ConnectionState {
+    /// The host has configured the port, which happens as soon as the cable is plugged in.
+    pub connected: bool,
+    /// Data Terminal Ready, set by the host while a terminal has the port open.
+    pub dtr: bool,
+}
+
+impl ConnectionState {
+    /// Whether a terminal is attached to read output, see [`DetachedPolicy`](print::DetachedPolicy).
+    pub const fn is_attached(self) -> bool {
+        self.connected && self.dtr
+    }
+}
+
+/// The current state of the serial port.
+pub fn connection() -> ConnectionState {
+    CONNECTION.try_get().unwrap_or_default()
+}
 
-static SERIAL_CONNECTED: AtomicBool = AtomicBool::new(false);
+/// Subscribe to changes of the [`ConnectionState`], `None` if there are already [`CONNECTION_WAITERS`] subscribers.
+pub fn subscribe() -> Option<ConnectionReceiver> {
+    CONNECTION.receiver()
+}
 
+/// Wait until a terminal is attached to the serial port.
 pub async fn wait_serial_up() {
-    while !SERIAL_CONNECTED.fetch_and(true, Ordering::Acquire) {
-        Timer::after_millis(10).await;
+    wait_attached(true).await;
+}
+
+/// Wait until the terminal detaches from the serial port, returning straight away if none is attached.
+pub async fn wait_serial_down() {
+    wait_attached(false).await;
+}
+
+async fn wait_attached(attached: bool) {
+    if let Some(mut receiver) = subscribe() {
+        receiver
+            .get_and(|state| state.is_attached() == attached)
+            .await;
+    } else {
+        // Every receiver is taken, so check now and then instead.
+        while connection().is_attached() != attached {
+            Timer::after_millis(10).await;
+        }
+    }
+}
+
+fn set_connection(state: ConnectionState) {
+    CONNECTION.sender().send_if_modified(|current| {
+        let changed = *current != Some(state);
+        *current = Some(state);
+        changed
+    });
+
+    if print::discarding() {
+        STD_OUT.clear();
+        STD_OUT_DRAINED.signal(());
     }
 }
 
-const USB_CONFIG: Config = {
+// The vendor and product ids and strings, set with the `USB_VID`, `USB_PID`, `USB_MANUFACTURER` and `USB_PRODUCT`
+// environment variables at build time.
+include!(concat!(env!("OUT_DIR"), "/usb_identity.rs"));
+
+/// The serial number is unique to each board so hosts can tell them apart, eg: in `/dev/serial/by-id`.
+const fn usb_config(serial_number: &'static str) -> Config<'static> {
     // Create embassy-usb Config
-    let mut config = Config::new(0xc0de, 0xcafe);
-    config.manufacturer = Some("Embassy");
-    config.product = Some("USB-serial example");
-    config.serial_number = Some("12345678");
+    let mut config = Config::new(USB_VID, USB_PID);
+    config.manufacturer = Some(USB_MANUFACTURER);
+    config.product = Some(USB_PRODUCT);
+    config.serial_number = Some(serial_number);
     config.max_power = 100;
     config.max_packet_size_0 = 64;
 
@@ -53,34 +148,72 @@ const USB_CONFIG: Config = {
     config.device_protocol = 0x01;
     config.composite_with_iads = true;
     config
-};
+}
+
+/// Everything the USB device borrows for as long as it runs.
+struct UsbResources {
+    config_descriptor: [u8; 512],
+    bos_descriptor: [u8; 256],
+    control_buf: [u8; 64],
+    serial_number: String<16>,
+    state: State<'static>,
+    log_state: State<'static>,
+}
 
 /// Initialise serial communication through the USB bus.
 /// This **must** be run before any usage of [`print!`] or [`println!`]
+///
+/// The device shows up as two serial ports, the console followed by one carrying the output of [`log`], and a network
+/// adapter with the `usb-ethernet` feature.
 #[embassy_executor::task]
 pub async fn init_serial(usb: USB) {
-    // Create the driver, from the HAL.
-    let driver = Driver::new(usb, Irqs);
+    static RESOURCES: StaticCell<UsbResources> = StaticCell::new();
 
-    // Create embassy-usb DeviceBuilder using the driver and config.
-    // It needs some buffers for building the descriptors.
-    let mut config_descriptor = [0; 256];
-    let mut bos_descriptor = [0; 256];
-    let mut control_buf = [0; 64];
+    let unique_id = storage::unique_id().await.unwrap_or_else(|error| {
+        log_warn!("Failed to read the board's unique id: {error}");
+        0
+    });
+    let mut serial_number = String::new();
+    write!(serial_number, "{unique_id:016X}").ok();
 
-    let mut state = State::new();
+    // Create the driver, from the HAL.
+    let driver = SupervisedUsb(Driver::new(usb, Irqs));
 
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors, which are static so classes can hand out `'static` drivers.
+    let UsbResources {
+        config_descriptor,
+        bos_descriptor,
+        control_buf,
+        serial_number,
+        state,
+        log_state,
+    } = RESOURCES.init(UsbResources {
+        config_descriptor: [0; 512],
+        bos_descriptor: [0; 256],
+        control_buf: [0; 64],
+        serial_number,
+        state: State::new(),
+        log_state: State::new(),
+    });
+
+    let serial_number: &'static String<16> = serial_number;
     let mut builder = Builder::new(
         driver,
-        USB_CONFIG,
-        &mut config_descriptor,
-        &mut bos_descriptor,
+        usb_config(serial_number.as_str()),
+        config_descriptor,
+        bos_descriptor,
         &mut [], // no msos descriptors
-        &mut control_buf,
+        control_buf,
     );
 
-    // Create classes on the builder.
-    let mut class = CdcAcmClass::new(&mut builder, &mut state, 64);
+    // Create classes on the builder, the console first so it gets the lower port number.
+    let class = CdcAcmClass::new(&mut builder, state, 64);
+    let (mut sender, mut receiver, control) = class.split_with_control();
+    let mut log_class = CdcAcmClass::new(&mut builder, log_state, 64);
+
+    #[cfg(feature = "usb-ethernet")]
+    let ethernet = crate::networking::usb_ethernet::add(&mut builder);
 
     // Build the builder.
     let mut usb = builder.build();
@@ -88,95 +221,129 @@ pub async fn init_serial(usb: USB) {
     // Run the USB device.
     let usb_fut = usb.run();
 
-    // Do stuff with the class!
-    let scan_fut = async {
+    let serial_fut = async {
+        set_connection(ConnectionState::default());
         loop {
-            class.wait_connection().await;
-            info!("Serial Connected");
-            SERIAL_CONNECTED.store(true, Ordering::SeqCst);
-            let _ = scan_serial(&mut class).await;
-            info!("Serial Disconnected");
+            receiver.wait_connection().await;
+            sender.wait_connection().await;
+            log_info!("Serial connected");
+            select(
+                read_serial(&mut receiver),
+                write_serial(&mut sender, &control),
+            )
+            .await;
+            set_connection(ConnectionState::default());
+            log_info!("Serial disconnected");
         }
     };
 
-    join(usb_fut, scan_fut).await;
-}
+    let log_fut = async {
+        loop {
+            log_class.wait_connection().await;
+            write_log(&mut log_class).await.ok();
+        }
+    };
 
-/// Cycles through reading data from the serial and placing it in [`STD_IN`] and flushing any data from [`STD_OUT`] to serial.
-#[allow(clippy::significant_drop_tightening)]
-async fn scan_serial<'d, T: Instance + 'd>(
-    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
-) -> Result<(), Disconnected> {
-    let mut buf = [0; 64];
-    loop {
-        let read_fut = class.read_packet(&mut buf);
-        let yield_fut = yield_now();
+    #[cfg(feature = "usb-ethernet")]
+    let ethernet_fut = ethernet.run();
+    #[cfg(not(feature = "usb-ethernet"))]
+    let ethernet_fut = core::future::pending::<()>();
 
-        match select(read_fut, yield_fut).await {
-            // Read serial if there is data
-            Either::First(read_count) => {
-                let data = &buf[..read_count?];
-                class.write_packet(data).await?;
+    join4(usb_fut, serial_fut, log_fut, ethernet_fut).await;
+}
 
-                let mut std_in = STD_IN.lock().await;
+/// Moves data from the host into [`STD_IN`], echoing it back.
+///
+/// Waits for room in [`STD_IN`] before reading the next packet, so the host holds data back until it's consumed.
+async fn read_serial(receiver: &mut Receiver<'static, UsbDriver>) -> Result<(), Disconnected> {
+    let mut buf = [0; PACKET_SIZE];
+    loop {
+        let count = receiver.read_packet(&mut buf).await?;
+        let data = &buf[..count];
+        SerialPort.write_all(data).await.ok();
+        STD_IN.write_all(data).await;
+        metrics::STDIN_HIGH_WATER.set_max(STD_IN.len().try_into().unwrap_or(i32::MAX));
+    }
+}
 
-                for byte in data {
-                    std_in.push_back(*byte).expect("STDIN buffer overflow");
-                }
+/// Sends [`STD_OUT`] to the host a packet at a time as soon as anything is written to it, while a terminal is
+/// attached, and keeps the [`ConnectionState`] up to date.
+async fn write_serial(
+    sender: &mut Sender<'static, UsbDriver>,
+    control: &ControlChanged<'static>,
+) -> Result<(), Disconnected> {
+    let mut packet = [0; PACKET_SIZE];
+    loop {
+        set_connection(ConnectionState {
+            connected: true,
+            dtr: sender.dtr(),
+        });
+
+        let read = async {
+            if sender.dtr() {
+                STD_OUT.read(&mut packet).await
+            } else {
+                core::future::pending().await
+            }
+        };
+        let Either::First(count) = select(read, control.control_changed()).await else {
+            // The conventional request to reboot into the bootloader, opening the port at 1200 baud then closing it.
+            if sender.line_coding().data_rate() == BOOTLOADER_TOUCH_BAUD && !sender.dtr() {
+                log_info!("1200 baud touch, rebooting into the bootloader");
+                system::enter_bootloader();
             }
-            // Otherwise flush STD_OUT
-            Either::Second(()) => {
-                let mut std_out = STD_OUT.lock().await;
+            continue;
+        };
 
-                if std_out.is_empty() {
-                    continue;
-                }
+        sender.write_packet(&packet[..count]).await?;
 
-                let packets = std_out.chunks(64);
+        if STD_OUT.is_empty() {
+            // A full packet doesn't end a transfer, so the host would sit on it until more output arrives.
+            if count == PACKET_SIZE {
+                sender.write_packet(&[]).await?;
+            }
+            STD_OUT_DRAINED.signal(());
+        }
+    }
+}
 
-                for packet in packets {
-                    class.write_packet(packet).await?;
-                }
+/// Sends [`log::LOG_OUT`] to the host a packet at a time, anything the host sends on the log port is ignored.
+async fn write_log(class: &mut CdcAcmClass<'static, UsbDriver>) -> Result<(), Disconnected> {
+    let mut packet = [0; PACKET_SIZE];
+    loop {
+        let count = log::LOG_OUT.read(&mut packet).await;
+        class.write_packet(&packet[..count]).await?;
 
-                std_out.clear();
-            }
+        // A full packet doesn't end a transfer, so the host would sit on it until more output arrives.
+        if count == PACKET_SIZE && log::LOG_OUT.is_empty() {
+            class.write_packet(&[]).await?;
         }
     }
 }
 
 /// Reads text from the [`STD_IN`] until a line feed or carraige return is read and appends it to the provided `buffer`.
 ///
-/// The first line feed or carraige return character is placed into the `buffer`, but any after is dropped.
-#[allow(clippy::significant_drop_tightening)]
+/// The line feed or carraige return is placed into the `buffer`, but a line feed following a carraige return is
+/// dropped.
 pub async fn read_line(buffer: &mut impl Write) -> Result<usize, fmt::Error> {
-    // Makes sure no other thread is able to read from stdin while this one is
-    static STD_IN_READ_LOCK: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
-
-    // Wait for other threads to finish.
+    // Wait for other tasks to finish.
     let lock = STD_IN_READ_LOCK.lock().await;
 
     let mut count = 0;
-    'a: loop {
-        Timer::after_millis(1).await;
-
-        let mut std_in = STD_IN.lock().await;
-
-        // Push each char onto the string.
-        while let Some(byte) = std_in.pop_front() {
-            buffer.write_char(byte as char)?;
-            count += 1;
-
-            if byte == b'\r' || byte == b'\n' {
-                // Remove any remaining cr or lf.
-                if std_in
-                    .front()
-                    .is_some_and(|byte| *byte == b'\r' || *byte == b'\n')
-                {
-                    std_in.pop_front();
-                }
-
-                break 'a;
-            }
+    let mut byte = [0];
+    loop {
+        STD_IN.read(&mut byte).await;
+        let [byte] = byte;
+
+        if AFTER_CR.swap(byte == b'\r', Ordering::Relaxed) && byte == b'\n' {
+            continue;
+        }
+
+        buffer.write_char(byte as char)?;
+        count += 1;
+
+        if byte == b'\r' || byte == b'\n' {
+            break;
         }
     }
 
@@ -187,12 +354,10 @@ pub async fn read_line(buffer: &mut impl Write) -> Result<usize, fmt::Error> {
 
 pub struct Disconnected;
 
-#[allow(clippy::fallible_impl_from)]
+/// Any endpoint error ends the current connection, a packet too large for the buffer can't happen as it is the max
+/// packet size, so rather than panicking it is treated like a disconnect and the port waits to be opened again.
 impl From<EndpointError> for Disconnected {
-    fn from(val: EndpointError) -> Self {
-        match val {
-            EndpointError::BufferOverflow => panic!("Buffer overflow"),
-            EndpointError::Disabled => Self {},
-        }
+    fn from(_: EndpointError) -> Self {
+        Self
     }
 }
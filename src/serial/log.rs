@@ -0,0 +1,139 @@
+//! Levelled log output, sent over the second USB serial port as well as to defmt.
+//!
+//! Logging never waits, lines that don't fit in the buffer because no host is reading the log port are dropped.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::Ordering;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pipe::Pipe;
+use embassy_time::Instant;
+use heapless::String;
+use portable_atomic::AtomicU8;
+
+use iot_device::metrics;
+
+const BUFFER_SIZE: usize = 2048;
+const LINE_SIZE: usize = 192;
+
+/// Formatted log lines waiting to be sent to the host.
+pub(super) static LOG_OUT: Pipe<CriticalSectionRawMutex, BUFFER_SIZE> = Pipe::new();
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => ({
+        #[allow(clippy::used_underscore_items)]
+        $crate::serial::log::_log($level, format_args!($($arg)*))
+    });
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => ($crate::log!($crate::serial::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => ($crate::log!($crate::serial::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => ($crate::log!($crate::serial::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => ($crate::log!($crate::serial::log::Level::Debug, $($arg)*));
+}
+
+/// How important a log line is, lines less important than [`level`] are skipped.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl Level {
+    pub const ALL: [Self; 4] = [Self::Error, Self::Warn, Self::Info, Self::Debug];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        }
+    }
+
+    const fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Error,
+            1 => Self::Warn,
+            3 => Self::Debug,
+            _ => Self::Info,
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn level() -> Level {
+    Level::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, args: fmt::Arguments<'_>) {
+    if level > self::level() {
+        return;
+    }
+
+    // Lines too long for the buffer are cut short.
+    let mut message = String::<LINE_SIZE>::new();
+    write!(Truncating(&mut message), "{args}").ok();
+
+    match level {
+        Level::Error => defmt::error!("{=str}", message),
+        Level::Warn => defmt::warn!("{=str}", message),
+        Level::Info => defmt::info!("{=str}", message),
+        Level::Debug => defmt::debug!("{=str}", message),
+    }
+
+    let millis = Instant::now().as_millis();
+    let mut line = String::<{ LINE_SIZE + 32 }>::new();
+    writeln!(
+        line,
+        "[{:>6}.{:03} {:<5}] {message}",
+        millis / 1000,
+        millis % 1000,
+        level.name()
+    )
+    .ok();
+
+    // Whole lines are dropped rather than splitting them.
+    if LOG_OUT.free_capacity() < line.len() {
+        metrics::LOG_DROPPED.increment();
+        return;
+    }
+    LOG_OUT.try_write(line.as_bytes()).ok();
+}
+
+/// Writes as much as fits into the line, as [`String`] drops the whole fragment when it doesn't fit.
+struct Truncating<'a>(&'a mut String<LINE_SIZE>);
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(self.0.capacity() - self.0.len());
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.0.push_str(&s[..end]).ok();
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use core::convert::Infallible;
+
+use embedded_io_async::{ErrorType, Read, ReadReady, Write, WriteReady};
+use iot_device::metrics;
+
+use crate::serial::{
+    print::{self, OverflowPolicy},
+    BUFFER_SIZE, PACKET_SIZE, STD_IN, STD_IN_READ_LOCK, STD_OUT,
+};
+
+/// A handle to the USB serial console, for use with anything built on [`embedded_io_async`].
+///
+/// Reads come from [`STD_IN`] and writes go to [`STD_OUT`] under the current [`OverflowPolicy`] and
+/// [`DetachedPolicy`](print::DetachedPolicy), so they share the console with [`print!`](crate::print) and
+/// [`read_line`](super::read_line).
+#[derive(Clone, Copy, Default)]
+pub struct SerialPort;
+
+impl ErrorType for SerialPort {
+    type Error = Infallible;
+}
+
+impl Read for SerialPort {
+    /// Wait until at least one byte is available, then read as many as fit in `buf`.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let lock = STD_IN_READ_LOCK.lock().await;
+        let count = STD_IN.read(buf).await;
+        drop(lock);
+        Ok(count)
+    }
+}
+
+impl ReadReady for SerialPort {
+    fn read_ready(&mut self) -> Result<bool, Self::Error> {
+        Ok(!STD_IN.is_empty())
+    }
+}
+
+impl Write for SerialPort {
+    /// Only waits when the policy is [`OverflowPolicy::Block`] and the buffer is full, otherwise all of `buf` is
+    /// accepted even if some of it is dropped.
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        match push_std_out(buf) {
+            0 => Ok(STD_OUT.write(buf).await),
+            written => Ok(written),
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        print::flush().await;
+        Ok(())
+    }
+}
+
+impl WriteReady for SerialPort {
+    fn write_ready(&mut self) -> Result<bool, Self::Error> {
+        Ok(print::overflow_policy() != OverflowPolicy::Block
+            || print::discarding()
+            || !STD_OUT.is_full())
+    }
+}
+
+/// Push `bytes` into [`STD_OUT`] as far as the [`OverflowPolicy`] allows without waiting.
+///
+/// Returns how many bytes were consumed, which is only less than `bytes.len()` when blocking. Everything is dropped
+/// while no terminal is attached under [`DetachedPolicy::Discard`].
+pub(super) fn push_std_out(bytes: &[u8]) -> usize {
+    if print::discarding() {
+        metrics::STDOUT_DROPPED.add(bytes.len().try_into().unwrap_or(u32::MAX));
+        return bytes.len();
+    }
+
+    let mut dropped = 0;
+    let consumed = match print::overflow_policy() {
+        OverflowPolicy::DropOldest => {
+            // Only the tail of anything longer than the buffer could ever be kept.
+            let kept = &bytes[bytes.len().saturating_sub(BUFFER_SIZE)..];
+            dropped += bytes.len() - kept.len();
+
+            let mut scratch = [0; PACKET_SIZE];
+            while STD_OUT.free_capacity() < kept.len() {
+                let room = (kept.len() - STD_OUT.free_capacity()).min(PACKET_SIZE);
+                dropped += STD_OUT.try_read(&mut scratch[..room]).unwrap_or(0);
+            }
+
+            STD_OUT.try_write(kept).ok();
+            bytes.len()
+        }
+        OverflowPolicy::DropNewest => {
+            let written = STD_OUT.try_write(bytes).unwrap_or(0);
+            dropped += bytes.len() - written;
+            bytes.len()
+        }
+        OverflowPolicy::Block => STD_OUT.try_write(bytes).unwrap_or(0),
+    };
+
+    metrics::STDOUT_DROPPED.add(dropped.try_into().unwrap_or(u32::MAX));
+    metrics::STDOUT_HIGH_WATER.set_max(STD_OUT.len().try_into().unwrap_or(i32::MAX));
+    consumed
+}
@@ -1,4 +1,9 @@
-use crate::serial::STD_OUT;
+use core::fmt::{self, Write};
+use core::sync::atomic::Ordering;
+
+use portable_atomic::AtomicU8;
+
+use crate::serial::{connection, port::push_std_out, STD_OUT, STD_OUT_DRAINED};
 
 #[macro_export]
 macro_rules! print {
@@ -14,9 +19,142 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// What [`print!`] does when [`STD_OUT`] is full because the host hasn't drained it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest buffered output to make room, so the most recent output is kept.
+    DropOldest = 0,
+    /// Discard whatever doesn't fit.
+    DropNewest = 1,
+    /// Wait until the host has drained enough, which stalls every printing task while no terminal is attached.
+    Block = 2,
+}
+
+impl OverflowPolicy {
+    pub const ALL: [Self; 3] = [Self::DropOldest, Self::DropNewest, Self::Block];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::DropOldest => "drop-oldest",
+            Self::DropNewest => "drop-newest",
+            Self::Block => "block",
+        }
+    }
+
+    const fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::DropNewest,
+            2 => Self::Block,
+            _ => Self::DropOldest,
+        }
+    }
+}
+
+static OVERFLOW_POLICY: AtomicU8 = AtomicU8::new(OverflowPolicy::DropOldest as u8);
+
+pub fn overflow_policy() -> OverflowPolicy {
+    OverflowPolicy::from_u8(OVERFLOW_POLICY.load(Ordering::Relaxed))
+}
+
+pub fn set_overflow_policy(policy: OverflowPolicy) {
+    OVERFLOW_POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// What happens to output while no terminal is attached to the serial port.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DetachedPolicy {
+    /// Keep output under the [`OverflowPolicy`], so it's sent once a terminal attaches.
+    Retain = 0,
+    /// Throw output away, including anything still buffered when the terminal detached.
+    Discard = 1,
+}
+
+impl DetachedPolicy {
+    pub const ALL: [Self; 2] = [Self::Retain, Self::Discard];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Retain => "retain",
+            Self::Discard => "discard",
+        }
+    }
+
+    const fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Discard,
+            _ => Self::Retain,
+        }
+    }
+}
+
+static DETACHED_POLICY: AtomicU8 = AtomicU8::new(DetachedPolicy::Retain as u8);
+
+pub fn detached_policy() -> DetachedPolicy {
+    DetachedPolicy::from_u8(DETACHED_POLICY.load(Ordering::Relaxed))
+}
+
+pub fn set_detached_policy(policy: DetachedPolicy) {
+    DETACHED_POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// Whether output is being thrown away because no terminal is attached, see [`DetachedPolicy::Discard`].
+pub(super) fn discarding() -> bool {
+    detached_policy() == DetachedPolicy::Discard && !connection().is_attached()
+}
+
+/// Wait until everything printed so far has been sent to the host.
+pub async fn flush() {
+    while !STD_OUT.is_empty() {
+        STD_OUT_DRAINED.wait().await;
+    }
+}
+
+/// Formats output into [`STD_OUT`] through [`push_std_out`], stopping once it would have to block.
+struct Writer {
+    /// Bytes of the output already written by an earlier attempt, which are skipped.
+    skip: usize,
+    /// Bytes of the output written so far, including skipped ones.
+    written: usize,
+    /// The first byte that didn't fit, if formatting stopped because the buffer was full.
+    blocked_on: Option<u8>,
+}
+
+impl Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let skipped = self.skip.min(s.len());
+        self.skip -= skipped;
+        let bytes = &s.as_bytes()[skipped..];
+
+        let consumed = push_std_out(bytes);
+        self.written += consumed;
+
+        if let Some(byte) = bytes.get(consumed) {
+            self.blocked_on = Some(*byte);
+            return Err(fmt::Error);
+        }
+        Ok(())
+    }
+}
+
 #[doc(hidden)]
-pub async fn _print(args: core::fmt::Arguments<'_>) {
-    let mut std_out = STD_OUT.lock().await;
+pub async fn _print(args: fmt::Arguments<'_>) {
+    let mut written = 0;
+
+    loop {
+        let mut writer = Writer {
+            skip: written,
+            written,
+            blocked_on: None,
+        };
+        fmt::write(&mut writer, args).ok();
+
+        let Some(byte) = writer.blocked_on else {
+            break;
+        };
 
-    core::fmt::write(&mut *std_out, args).expect("STDOUT buffer overflow");
+        // Wait for room for the byte that didn't fit, then repeat formatting from the start, skipping what has
+        // already been written.
+        STD_OUT.write_all(&[byte]).await;
+        written = writer.written + 1;
+    }
 }
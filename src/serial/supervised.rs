@@ -0,0 +1,83 @@
+//! A wrapper around the USB driver whose bus checks in with the watchdog each time round the USB device's loop.
+
+use embassy_usb::driver::{
+    Bus, Driver, EndpointAddress, EndpointAllocError, EndpointType, Event, Unsupported,
+};
+
+use crate::system::watchdog::{self, Task};
+
+pub struct SupervisedUsb<D>(pub D);
+
+impl<'d, D: Driver<'d>> Driver<'d> for SupervisedUsb<D> {
+    type EndpointOut = D::EndpointOut;
+    type EndpointIn = D::EndpointIn;
+    type ControlPipe = D::ControlPipe;
+    type Bus = SupervisedBus<D::Bus>;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        self.0
+            .alloc_endpoint_out(ep_type, max_packet_size, interval_ms)
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        self.0
+            .alloc_endpoint_in(ep_type, max_packet_size, interval_ms)
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        let (bus, control) = self.0.start(control_max_packet_size);
+        (SupervisedBus(bus), control)
+    }
+}
+
+/// The bus the USB device polls for events at the top of its loop.
+pub struct SupervisedBus<B>(B);
+
+impl<B: Bus> Bus for SupervisedBus<B> {
+    async fn enable(&mut self) {
+        self.0.enable().await;
+    }
+
+    async fn disable(&mut self) {
+        self.0.disable().await;
+    }
+
+    /// Checks in at least every [`watchdog::CHECK_IN_INTERVAL`] while waiting for the next event.
+    async fn poll(&mut self) -> Event {
+        loop {
+            if let Some(event) = watchdog::check_in_within(Task::Serial, self.0.poll()).await {
+                return event;
+            }
+        }
+    }
+
+    fn endpoint_set_enabled(&mut self, ep_addr: EndpointAddress, enabled: bool) {
+        self.0.endpoint_set_enabled(ep_addr, enabled);
+    }
+
+    fn endpoint_set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
+        self.0.endpoint_set_stalled(ep_addr, stalled);
+    }
+
+    fn endpoint_is_stalled(&mut self, ep_addr: EndpointAddress) -> bool {
+        self.0.endpoint_is_stalled(ep_addr)
+    }
+
+    fn force_reset(&mut self) -> Result<(), Unsupported> {
+        self.0.force_reset()
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        self.0.remote_wakeup().await
+    }
+}
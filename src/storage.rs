@@ -0,0 +1,169 @@
+//! Persistent records kept in the `STORAGE` region of flash reserved in `memory.x`.
+//!
+//! Each [`Slot`] occupies one erase sector holding a single record, which is replaced as a whole on every write.
+
+use embassy_rp::{
+    flash::{self, Blocking, Flash, ERASE_SIZE},
+    peripherals::FLASH,
+};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    mutex::{MappedMutexGuard, Mutex, MutexGuard},
+};
+use thiserror_no_std::Error;
+
+/// Both boards only use the first 2MB of flash, see `memory-pico2-w.x`.
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// Offset of the `STORAGE` region from the start of flash, must match `memory.x`.
+const STORAGE_OFFSET: u32 = 0x1F_C000;
+
+/// Identifies a record as written by [`store`], "STOR" in ascii.
+const MAGIC: u32 = 0x5354_4f52;
+const HEADER_SIZE: usize = 12;
+
+#[allow(clippy::cast_possible_truncation)]
+const SECTOR_SIZE: u32 = ERASE_SIZE as u32;
+#[allow(clippy::cast_possible_truncation)]
+const DATA_OFFSET: u32 = HEADER_SIZE as u32;
+
+pub const MAX_RECORD_SIZE: usize = ERASE_SIZE - HEADER_SIZE;
+
+pub type FlashDevice = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
+
+static FLASH_DEVICE: Mutex<CriticalSectionRawMutex, Option<FlashDevice>> = Mutex::new(None);
+
+#[derive(Clone, Copy)]
+pub enum Slot {
+    NetworkConfig = 0,
+}
+
+impl Slot {
+    const fn offset(self) -> u32 {
+        STORAGE_OFFSET + self as u32 * SECTOR_SIZE
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[cfg(feature = "pico2-w")]
+    #[error("Failed to read OTP: `{0:?}`")]
+    OtpError(embassy_rp::otp::Error),
+    #[error("Storage has not been initialised")]
+    Uninitialised,
+    #[error("No record has been stored")]
+    Empty,
+    #[error("The stored record is corrupted")]
+    Corrupted,
+    #[error("Record is larger than {MAX_RECORD_SIZE} bytes")]
+    TooLarge,
+    #[error("Failed to access flash: `{0:?}`")]
+    FlashError(flash::Error),
+}
+
+impl From<flash::Error> for StorageError {
+    fn from(value: flash::Error) -> Self {
+        Self::FlashError(value)
+    }
+}
+
+/// Take ownership of the flash, this **must** be run before any other storage function.
+pub async fn init(flash: FLASH) {
+    *FLASH_DEVICE.lock().await = Some(Flash::new_blocking(flash));
+}
+
+/// An id unique to each board, from the flash chip on the Pico W and from OTP on the Pico 2 W.
+pub async fn unique_id() -> Result<u64, StorageError> {
+    #[cfg(feature = "pico-w")]
+    {
+        let mut id = [0; 8];
+        lock().await?.blocking_unique_id(&mut id)?;
+        Ok(u64::from_be_bytes(id))
+    }
+    #[cfg(feature = "pico2-w")]
+    {
+        embassy_rp::otp::get_chipid().map_err(StorageError::OtpError)
+    }
+}
+
+/// Take exclusive access to the whole flash, for regions outside of `STORAGE` such as the update partitions.
+///
+/// Records can't be loaded or stored until the returned guard is dropped.
+pub async fn lock(
+) -> Result<MappedMutexGuard<'static, CriticalSectionRawMutex, FlashDevice>, StorageError> {
+    let flash = FLASH_DEVICE.lock().await;
+    if flash.is_none() {
+        return Err(StorageError::Uninitialised);
+    }
+    Ok(MutexGuard::map(flash, |flash| {
+        flash.as_mut().expect("Flash was checked to be initialised")
+    }))
+}
+
+/// Read the record in `slot` into `buffer`, returning the part of `buffer` it occupies.
+#[allow(clippy::significant_drop_tightening)]
+pub async fn load(slot: Slot, buffer: &mut [u8]) -> Result<&[u8], StorageError> {
+    let mut flash = FLASH_DEVICE.lock().await;
+    let flash = flash.as_mut().ok_or(StorageError::Uninitialised)?;
+
+    let mut header = [0; HEADER_SIZE];
+    flash.blocking_read(slot.offset(), &mut header)?;
+    let [magic, length, checksum] = [0, 4, 8].map(|index| {
+        u32::from_le_bytes([
+            header[index],
+            header[index + 1],
+            header[index + 2],
+            header[index + 3],
+        ])
+    });
+
+    if magic != MAGIC {
+        return Err(StorageError::Empty);
+    }
+
+    let data = usize::try_from(length)
+        .ok()
+        .filter(|length| *length <= MAX_RECORD_SIZE)
+        .and_then(|length| buffer.get_mut(..length))
+        .ok_or(StorageError::Corrupted)?;
+    flash.blocking_read(slot.offset() + DATA_OFFSET, data)?;
+
+    if fnv1a(data) != checksum {
+        return Err(StorageError::Corrupted);
+    }
+
+    Ok(data)
+}
+
+/// Replace the record in `slot` with `data`.
+#[allow(clippy::significant_drop_tightening)]
+pub async fn store(slot: Slot, data: &[u8]) -> Result<(), StorageError> {
+    if data.len() > MAX_RECORD_SIZE {
+        return Err(StorageError::TooLarge);
+    }
+
+    let mut flash = FLASH_DEVICE.lock().await;
+    let flash = flash.as_mut().ok_or(StorageError::Uninitialised)?;
+
+    let length = u32::try_from(data.len()).map_err(|_| StorageError::TooLarge)?;
+    let mut header = [0; HEADER_SIZE];
+    header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    header[4..8].copy_from_slice(&length.to_le_bytes());
+    header[8..].copy_from_slice(&fnv1a(data).to_le_bytes());
+
+    flash.blocking_erase(slot.offset(), slot.offset() + SECTOR_SIZE)?;
+    flash.blocking_write(slot.offset() + DATA_OFFSET, data)?;
+    // The header is written last so an interrupted write is never mistaken for a valid record.
+    flash.blocking_write(slot.offset(), &header)?;
+
+    Ok(())
+}
+
+/// 32 bit FNV-1a hash, used to detect corrupted records.
+///
+/// `build.rs` has a copy for checking the CYW43 firmware, the two must match.
+pub fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    })
+}
@@ -0,0 +1,75 @@
+//! Rebooting the device, either back into the application or into the ROM's USB mass storage bootloader, resetting it
+//! when tasks stop responding, reporting why it last crashed and how much memory it uses, and sleeping between
+//! uploads.
+
+pub mod crash;
+pub mod memory;
+pub mod sleep;
+pub mod watchdog;
+
+use cortex_m::peripheral::SCB;
+use embassy_rp::{peripherals::WATCHDOG, watchdog::Watchdog};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{with_timeout, Duration};
+
+use crate::serial::print;
+
+/// How long the serial port is given to flush before rebooting.
+const FLUSH_TIMEOUT: Duration = Duration::from_millis(500);
+
+static WATCHDOG_DEVICE: Mutex<CriticalSectionRawMutex, Option<Watchdog>> = Mutex::new(None);
+
+/// Copy the `N` bytes at `pointer`, in RAM which isn't zeroed on boot, as whatever they hold.
+///
+/// Each byte is a volatile read, which takes the value the RAM holds rather than leaving the compiler to treat it as
+/// uninitialised, so records can be validated as bytes before they are trusted.
+///
+/// # Safety
+///
+/// `pointer` must be valid for reads of `N` bytes.
+pub unsafe fn read_noinit<const N: usize>(pointer: *const u8) -> [u8; N] {
+    let mut bytes = [0; N];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = pointer.add(index).read_volatile();
+    }
+    bytes
+}
+
+/// Take ownership of the watchdog, which [`reboot`] resets the device with.
+pub async fn init(watchdog: WATCHDOG) {
+    *WATCHDOG_DEVICE.lock().await = Some(Watchdog::new(watchdog));
+}
+
+/// Reboot the device through the watchdog, giving the serial port a moment to flush first.
+pub async fn reboot() -> ! {
+    with_timeout(FLUSH_TIMEOUT, print::flush()).await.ok();
+
+    if let Some(watchdog) = WATCHDOG_DEVICE.lock().await.as_mut() {
+        watchdog.trigger_reset();
+    }
+    // Only reached if the watchdog wasn't initialised.
+    SCB::sys_reset()
+}
+
+/// Reboot into the USB mass storage bootloader, as if BOOTSEL was held, giving the serial port a moment to flush
+/// first.
+pub async fn reboot_to_bootloader() -> ! {
+    with_timeout(FLUSH_TIMEOUT, print::flush()).await.ok();
+    enter_bootloader()
+}
+
+/// Reboot into the USB mass storage bootloader straight away.
+pub fn enter_bootloader() -> ! {
+    #[cfg(feature = "pico-w")]
+    embassy_rp::rom_data::reset_to_usb_boot(0, 0);
+
+    #[cfg(feature = "pico2-w")]
+    {
+        /// `BOOTSEL` reboot type, without returning on success.
+        const REBOOT_FLAGS: u32 = 0x0002 | 0x0100;
+        embassy_rp::rom_data::reboot(REBOOT_FLAGS, 10, 0, 0);
+    }
+
+    // The ROM doesn't return, but in case it does don't carry on running.
+    SCB::sys_reset()
+}
@@ -0,0 +1,191 @@
+//! Crash reports kept across resets.
+//!
+//! The panic handler writes where and why the firmware panicked, along with the uptime, into the `CRASH_REPORT`
+//! region of RAM, which isn't zeroed on boot, then resets. [`init`] picks the report up on the next boot, it's then
+//! printed over serial and sent to `CRASH_REPORT_URL` with [`upload`], if one was set at build time.
+
+use core::fmt;
+#[cfg(target_os = "none")]
+use core::fmt::Write;
+use core::mem::{offset_of, size_of, MaybeUninit};
+use core::ptr::addr_of_mut;
+#[cfg(target_os = "none")]
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use heapless::String;
+use reqwless::request::Method;
+use serde::Serialize;
+
+use crate::networking::{Client, Connected};
+use crate::storage::fnv1a;
+use crate::{log_info, log_warn};
+
+include!(concat!(env!("OUT_DIR"), "/crash_report_url.rs"));
+
+/// The longest panic message kept, the whole record must fit in the `CRASH_REPORT` region in memory.x.
+const MESSAGE_SIZE: usize = 960;
+/// Marks the record as written by the panic handler, "CRSH" in ascii.
+const MAGIC: u32 = 0x4352_5348;
+
+#[repr(C)]
+struct Record {
+    magic: u32,
+    /// [`fnv1a`] of the message, as the region holds garbage after a power cycle.
+    checksum: u32,
+    uptime_millis: u64,
+    length: u32,
+    message: [u8; MESSAGE_SIZE],
+}
+
+#[link_section = ".crash_report"]
+static mut RECORD: MaybeUninit<Record> = MaybeUninit::uninit();
+
+/// The crash before the last reset, until it has been uploaded.
+static PREVIOUS: Mutex<CriticalSectionRawMutex, Option<Report>> = Mutex::new(None);
+
+/// A panic recorded before the last reset.
+#[derive(Clone, Serialize)]
+pub struct Report {
+    /// How long the device had been running for, in milliseconds.
+    uptime: u64,
+    /// The panic message, prefixed with where it panicked.
+    message: String<MESSAGE_SIZE>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:03}s after boot, {}",
+            self.uptime / 1000,
+            self.uptime % 1000,
+            self.message
+        )
+    }
+}
+
+/// Take the report left by the panic handler, clearing it so it's only reported once.
+pub async fn init() {
+    let pointer = addr_of_mut!(RECORD).cast::<Record>();
+    // Safety: the record is only read as bytes, and only the panic handler writes to it otherwise.
+    let bytes: [u8; size_of::<Record>()] = unsafe {
+        let bytes = super::read_noinit(pointer.cast());
+        addr_of_mut!((*pointer).magic).write_volatile(0);
+        bytes
+    };
+
+    let field = |offset: usize| {
+        u32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap_or_default())
+    };
+    let length = field(offset_of!(Record, length)) as usize;
+    let message = &bytes[offset_of!(Record, message)..][..MESSAGE_SIZE];
+    if field(offset_of!(Record, magic)) != MAGIC
+        || length > MESSAGE_SIZE
+        || fnv1a(&message[..length]) != field(offset_of!(Record, checksum))
+    {
+        return;
+    }
+
+    // Safety: the bytes were written by the panic handler as a `Record`, which is plain integers.
+    let record = unsafe { bytes.as_ptr().cast::<Record>().read_unaligned() };
+    let (uptime, message) = (record.uptime_millis, record.message);
+
+    // The message may have been cut short in the middle of a character.
+    let message = match core::str::from_utf8(&message[..length]) {
+        Ok(message) => message,
+        Err(error) => core::str::from_utf8(&message[..error.valid_up_to()]).unwrap_or_default(),
+    };
+    let report = Report {
+        uptime,
+        message: message.try_into().unwrap_or_default(),
+    };
+    log_warn!("Crashed before the last reset: {report}");
+    *PREVIOUS.lock().await = Some(report);
+}
+
+/// The crash before the last reset, if there was one and it hasn't been uploaded yet.
+pub async fn previous() -> Option<Report> {
+    PREVIOUS.lock().await.clone()
+}
+
+/// Send the crash before the last reset to `CRASH_REPORT_URL` as json, keeping it to try again if that fails.
+pub async fn upload(client: &Client<Connected>) {
+    let Some(url) = REPORT_URL else {
+        return;
+    };
+    let Some(report) = previous().await else {
+        return;
+    };
+
+    let Ok(body) = serde_json_core::to_string::<_, { MESSAGE_SIZE * 2 }>(&report) else {
+        log_warn!("Crash report is too long to upload");
+        return;
+    };
+    match client
+        .request(
+            url,
+            Method::POST,
+            Some(&[("Content-Type", "application/json")]),
+            Some(&body),
+        )
+        .await
+    {
+        Ok(_) => {
+            log_info!("Uploaded crash report to `{url}`");
+            *PREVIOUS.lock().await = None;
+        }
+        Err(error) => log_warn!("Failed to upload crash report: `{error}`"),
+    }
+}
+
+/// Writes as much as fits into `buffer`, dropping the rest.
+#[cfg(target_os = "none")]
+struct Truncating<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+}
+
+#[cfg(target_os = "none")]
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let length = s.len().min(self.buffer.len() - self.length);
+        self.buffer[self.length..self.length + length].copy_from_slice(&s.as_bytes()[..length]);
+        self.length += length;
+        Ok(())
+    }
+}
+
+/// Record the panic for the next boot, then reset.
+#[cfg(target_os = "none")]
+#[panic_handler]
+#[allow(clippy::cast_possible_truncation)]
+fn panic(info: &core::panic::PanicInfo<'_>) -> ! {
+    static PANICKED: portable_atomic::AtomicBool = portable_atomic::AtomicBool::new(false);
+
+    cortex_m::interrupt::disable();
+
+    // A panic while recording the last one skips straight to the reset.
+    if !PANICKED.swap(true, Ordering::Relaxed) {
+        // Safety: interrupts are disabled and the second core isn't used, so nothing else can touch the record.
+        let record = unsafe { &mut *addr_of_mut!(RECORD).cast::<Record>() };
+
+        let mut writer = Truncating {
+            buffer: &mut record.message,
+            length: 0,
+        };
+        write!(writer, "{info}").ok();
+        let length = writer.length;
+
+        record.uptime_millis = embassy_time::Instant::now().as_millis();
+        record.length = length as u32;
+        record.checksum = fnv1a(&record.message[..length]);
+        // The magic goes last, so a half written record is never picked up.
+        compiler_fence(Ordering::SeqCst);
+        record.magic = MAGIC;
+
+        defmt::error!("{}", defmt::Display2Format(info));
+    }
+
+    cortex_m::peripheral::SCB::sys_reset()
+}
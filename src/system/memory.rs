@@ -0,0 +1,87 @@
+//! How much of the main stack and the executor's task arena has been used.
+//!
+//! Before RAM is initialised the free stack is painted with [`PAINT`], so the deepest the stack has reached is the
+//! first painted word left from the bottom. The task arena is taken out of `.bss` in memory.x and zeroed here
+//! instead, so the arena's bump allocation shows up as the last word written to. The link fails if the arena isn't
+//! found by its symbol name.
+
+use core::mem::size_of;
+use core::ptr::{addr_of, addr_of_mut};
+
+use serde::Serialize;
+
+/// Written over the free stack, words still holding this haven't been used.
+const PAINT: u32 = 0x5354_4b21;
+/// Left unpainted just below the stack pointer, as a margin for [`paint`] itself.
+const PAINT_MARGIN: usize = 256;
+
+extern "C" {
+    /// The top of the main stack.
+    static _stack_start: u32;
+    /// The lowest address the main stack can grow to, see memory.x.
+    static __stack_limit: u32;
+    static mut __task_arena_start: u32;
+    static mut __task_arena_end: u32;
+}
+
+/// How much of a region of memory has been used, in bytes.
+#[derive(Clone, Copy, Serialize)]
+pub struct Usage {
+    pub used: usize,
+    pub size: usize,
+}
+
+/// Zero the task arena and paint the free stack, run before RAM is initialised.
+#[cortex_m_rt::pre_init]
+unsafe fn paint() {
+    // Safety: nothing has been spawned yet, so the arena isn't in use, and the painted stack is below this frame.
+    unsafe {
+        let mut word = addr_of_mut!(__task_arena_start);
+        while word < addr_of_mut!(__task_arena_end) {
+            word.write_volatile(0);
+            word = word.add(1);
+        }
+
+        let mut word = addr_of!(__stack_limit).cast_mut();
+        let end = cortex_m::register::msp::read() as usize - PAINT_MARGIN;
+        while (word as usize) < end {
+            word.write_volatile(PAINT);
+            word = word.add(1);
+        }
+    }
+}
+
+/// The deepest the main stack has reached, interrupt handlers and the supervisor's executor included.
+pub fn stack() -> Usage {
+    let (limit, start) = (addr_of!(__stack_limit), addr_of!(_stack_start));
+    let mut word = limit;
+    // Safety: the stack only grows down to its limit, and words between there and the top are always readable.
+    while word < start && unsafe { word.read_volatile() } == PAINT {
+        word = unsafe { word.add(1) };
+    }
+
+    Usage {
+        used: start as usize - word as usize,
+        size: start as usize - limit as usize,
+    }
+}
+
+/// How much of the executor's task arena has been handed out to tasks.
+///
+/// This is an estimate from the last word written to, the final word is skipped as it may be the arena's own
+/// pointer rather than part of a task.
+pub fn task_arena() -> Usage {
+    let start = addr_of!(__task_arena_start);
+    let words = (addr_of!(__task_arena_end) as usize - start as usize) / size_of::<u32>();
+
+    let mut used = words.saturating_sub(1);
+    // Safety: the arena lies between the two symbols, and tasks are never freed so it's only ever written more of.
+    while used > 0 && unsafe { start.add(used - 1).read_volatile() } == 0 {
+        used -= 1;
+    }
+
+    Usage {
+        used: used * size_of::<u32>(),
+        size: words * size_of::<u32>(),
+    }
+}
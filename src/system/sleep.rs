@@ -0,0 +1,177 @@
+//! Deep sleep between uploads, for battery powered devices.
+//!
+//! The CYW43 is powered down through its `WL_ON` pin, then on the Pico W the RP2040 sleeps with only the RTC clocked
+//! until its alarm goes off. The device resets on waking, so it starts over and rejoins the network. embassy-rp has no
+//! RTC driver for the RP2350, so the Pico 2 W halts the core until a timer alarm instead, which keeps the clocks
+//! running but not the core.
+
+use cortex_m::peripheral::{NVIC, SCB};
+use embassy_rp::gpio::{Level, Output};
+use embassy_rp::interrupt::InterruptExt;
+use embassy_rp::pac;
+use embassy_rp::peripherals::PIN_23;
+use embassy_time::{with_timeout, Duration};
+
+use super::FLUSH_TIMEOUT;
+use crate::log_info;
+use crate::serial::print;
+
+/// The words of the NVIC's enable registers the chip's interrupts take up.
+#[cfg(feature = "pico-w")]
+const NVIC_WORDS: usize = 1;
+#[cfg(feature = "pico2-w")]
+const NVIC_WORDS: usize = 2;
+
+/// Disable every interrupt but `irq` in the NVIC. With interrupts masked a pending one still wakes the core, so any
+/// other left enabled, such as USB or the timer, could keep it awake.
+///
+/// # Safety
+///
+/// Interrupts must be disabled, so `irq`'s handler never runs, and nothing else may be left needing the others.
+unsafe fn enable_only(irq: impl InterruptExt) {
+    let nvic = &*NVIC::PTR;
+    for word in &nvic.icer[..NVIC_WORDS] {
+        word.write(u32::MAX);
+    }
+    irq.enable();
+}
+
+#[cfg(feature = "pico-w")]
+mod rtc {
+    use embassy_rp::interrupt::{self, InterruptExt};
+    use embassy_rp::pac;
+    use embassy_rp::peripherals::RTC;
+    use embassy_rp::rtc::{DateTime, DateTimeFilter, DayOfWeek, Rtc};
+    use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+    use embassy_time::Duration;
+
+    /// The RTC is set to this before sleeping, so the alarm only has to count from midnight on the first.
+    const EPOCH: DateTime = DateTime {
+        year: 2000,
+        month: 1,
+        day: 1,
+        day_of_week: DayOfWeek::Saturday,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+    /// The alarm can't go past the end of the month it was set in.
+    const MAX_SLEEP: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+    pub static RTC_DEVICE: Mutex<CriticalSectionRawMutex, Option<Rtc<'static, RTC>>> =
+        Mutex::new(None);
+
+    /// Sleep until `duration` has passed, returning once the alarm is pending or `false` if the RTC isn't running.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn sleep(rtc: &mut Rtc<'static, RTC>, duration: Duration) -> bool {
+        let secs = duration.as_secs().min(MAX_SLEEP.as_secs());
+        if rtc.set_datetime(EPOCH).is_err() {
+            return false;
+        }
+        rtc.schedule_alarm(
+            DateTimeFilter::default()
+                .day(1 + (secs / 86400) as u8)
+                .hour((secs / 3600 % 24) as u8)
+                .minute((secs / 60 % 60) as u8)
+                .second((secs % 60) as u8),
+        );
+
+        // Only the RTC stays clocked, and only its alarm may wake the core, without being handled as interrupts are
+        // masked.
+        pac::CLOCKS.sleep_en0().write(|w| w.set_clk_rtc_rtc(true));
+        pac::CLOCKS.sleep_en1().write(|_| {});
+        interrupt::RTC_IRQ.unpend();
+        // Safety: interrupts are disabled, and nothing else runs before the reset.
+        unsafe { super::enable_only(interrupt::RTC_IRQ) };
+        // Safety: only this core is running, and it resets on waking.
+        unsafe { cortex_m::Peripherals::steal() }
+            .SCB
+            .set_sleepdeep();
+
+        while !interrupt::RTC_IRQ.is_pending() {
+            cortex_m::asm::wfi();
+        }
+        true
+    }
+}
+
+mod timer {
+    use embassy_rp::interrupt::{self, InterruptExt};
+    use embassy_rp::pac;
+    use embassy_time::{Duration, Instant};
+
+    #[cfg(feature = "pico2-w")]
+    use {interrupt::TIMER0_IRQ_1 as ALARM_IRQ, pac::TIMER0 as TIMER};
+    #[cfg(feature = "pico-w")]
+    use {interrupt::TIMER_IRQ_1 as ALARM_IRQ, pac::TIMER};
+
+    /// The time driver only uses the first alarm.
+    const ALARM: usize = 1;
+    /// The alarm compares the low 32 bits of the microsecond counter, so longer sleeps are made of several waits.
+    const MAX_WAIT_MICROS: u64 = 1 << 31;
+    /// Shorter waits aren't worth arming the alarm for, which wouldn't fire if its time passed while being set.
+    const MIN_WAIT: Duration = Duration::from_micros(100);
+
+    /// Halt the core until `duration` has passed, waking on each alarm without handling it as interrupts are masked.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn sleep(duration: Duration) {
+        let deadline = Instant::now() + duration;
+
+        // Safety: interrupts are disabled, and nothing else runs before the reset.
+        unsafe { super::enable_only(ALARM_IRQ) };
+        TIMER.inte().modify(|w| w.set_alarm(ALARM, true));
+
+        loop {
+            let now = Instant::now();
+            if deadline.saturating_duration_since(now) < MIN_WAIT {
+                break;
+            }
+            let wait = (deadline - now).as_micros().min(MAX_WAIT_MICROS) as u32;
+
+            TIMER.intr().write(|w| w.set_alarm(ALARM, true));
+            ALARM_IRQ.unpend();
+            let target = TIMER.timerawl().read().wrapping_add(wait);
+            TIMER.alarm(ALARM).write_value(target);
+
+            while !ALARM_IRQ.is_pending() {
+                cortex_m::asm::wfi();
+            }
+        }
+    }
+}
+
+/// Take ownership of the RTC, which [`deep_sleep`] wakes the device with.
+#[cfg(feature = "pico-w")]
+pub async fn init(rtc: embassy_rp::peripherals::RTC) {
+    *rtc::RTC_DEVICE.lock().await = Some(embassy_rp::rtc::Rtc::new(rtc));
+}
+
+/// Power the radio down and sleep for `duration`, then reset the device to start over.
+///
+/// On the Pico W this is at most 30 days.
+pub async fn deep_sleep(duration: Duration) -> ! {
+    log_info!("Sleeping for {}s", duration.as_secs());
+    with_timeout(FLUSH_TIMEOUT, print::flush()).await.ok();
+
+    #[cfg(feature = "pico-w")]
+    let rtc = rtc::RTC_DEVICE.lock().await.take();
+
+    // Nothing else runs from here on, so tasks don't notice the radio going away.
+    cortex_m::interrupt::disable();
+    pac::WATCHDOG.ctrl().modify(|w| w.set_enable(false));
+
+    // Safety: the cyw43 runner owns the pin, but it never runs again before the reset.
+    let wl_on = Output::new(unsafe { PIN_23::steal() }, Level::Low);
+    // Keep driving the pin low, dropping it would leave it floating.
+    core::mem::forget(wl_on);
+
+    #[cfg(feature = "pico-w")]
+    if let Some(mut rtc) = rtc {
+        if rtc::sleep(&mut rtc, duration) {
+            SCB::sys_reset()
+        }
+    }
+
+    timer::sleep(duration);
+    SCB::sys_reset()
+}
@@ -0,0 +1,203 @@
+//! A task watchdog on top of the hardware watchdog.
+//!
+//! Each supervised [`Task`] checks in from its own loop, so a task stuck on an await stops checking in even while the
+//! rest of the executor carries on. Waits which can rightly take any amount of time, such as for input, are wrapped
+//! in [`idle`] instead. [`supervise`] runs on a higher priority executor so it keeps going while a task hogs the main
+//! one, and stops feeding the hardware watchdog once any task misses its deadline. The tasks that missed it are kept
+//! in a scratch register across the reset, see [`missed_before_reset`].
+
+use core::fmt;
+use core::future::Future;
+use core::sync::atomic::Ordering;
+
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use portable_atomic::{AtomicU32, AtomicU8};
+
+use super::WATCHDOG_DEVICE;
+use crate::log_warn;
+
+/// How often tasks check in while they have nothing to do.
+pub const CHECK_IN_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a task can go without checking in before the device is reset.
+const DEADLINE: Duration = Duration::from_secs(5);
+/// The main task checks in between link samples and after each round of requests, which take longer.
+const MAIN_DEADLINE: Duration = Duration::from_secs(90);
+/// How long the hardware watchdog waits to be fed, kept under the RP2040's limit of about 8.3 seconds.
+const HARDWARE_PERIOD: Duration = Duration::from_secs(8);
+
+/// The scratch register the missed tasks are recorded in, registers 4 to 7 are used by the boot ROM.
+const SCRATCH_INDEX: usize = 0;
+/// Marks the scratch register as written by [`supervise`], "WD" in ascii.
+const SCRATCH_MAGIC: u32 = 0x5744_0000;
+
+/// The tasks the watchdog supervises.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Task {
+    Cyw43 = 0,
+    Net = 1,
+    Serial = 2,
+    Main = 3,
+}
+
+impl Task {
+    pub const ALL: [Self; 4] = [Self::Cyw43, Self::Net, Self::Serial, Self::Main];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Cyw43 => "cyw43",
+            Self::Net => "net",
+            Self::Serial => "serial",
+            Self::Main => "main",
+        }
+    }
+
+    const fn deadline(self) -> Duration {
+        match self {
+            Self::Main => MAIN_DEADLINE,
+            Self::Cyw43 | Self::Net | Self::Serial => DEADLINE,
+        }
+    }
+
+    const fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// When each task last checked in, in milliseconds since boot.
+static CHECK_INS: [AtomicU32; Task::ALL.len()] = [const { AtomicU32::new(0) }; Task::ALL.len()];
+/// The tasks which have checked in at least once, only those are supervised.
+static REGISTERED: AtomicU8 = AtomicU8::new(0);
+/// The tasks waiting in [`idle`], which aren't expected to check in.
+static IDLE: AtomicU8 = AtomicU8::new(0);
+/// The tasks which missed their deadline before the last reset.
+static MISSED_BEFORE_RESET: AtomicU8 = AtomicU8::new(0);
+
+/// A set of [`Task`]s, displayed as a list of their names.
+#[derive(Clone, Copy)]
+pub struct Tasks(u8);
+
+impl fmt::Display for Tasks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut tasks = Task::ALL
+            .into_iter()
+            .filter(|task| self.0 & task.bit() != 0);
+        if let Some(task) = tasks.next() {
+            f.write_str(task.name())?;
+        }
+        for task in tasks {
+            write!(f, ", {}", task.name())?;
+        }
+        Ok(())
+    }
+}
+
+/// The tasks which missed their deadline, if the last reset was caused by the watchdog.
+pub fn missed_before_reset() -> Option<Tasks> {
+    match MISSED_BEFORE_RESET.load(Ordering::Relaxed) {
+        0 => None,
+        missed => Some(Tasks(missed)),
+    }
+}
+
+/// Record that `task` is still making progress, starting its supervision on the first call.
+pub fn check_in(task: Task) {
+    CHECK_INS[task as usize].store(now_millis(), Ordering::Relaxed);
+    REGISTERED.fetch_or(task.bit(), Ordering::Relaxed);
+}
+
+/// Check in for `task`, then wait for `future` for up to [`CHECK_IN_INTERVAL`], returning `None` if it didn't finish.
+///
+/// For the wait at the top of a task's loop, so the loop keeps going round and checking in while there's nothing to
+/// do, and stops when the task is stuck anywhere else.
+pub async fn check_in_within<F: Future>(task: Task, future: F) -> Option<F::Output> {
+    check_in(task);
+    with_timeout(CHECK_IN_INTERVAL, future).await.ok()
+}
+
+/// Wait for `future` without `task` having to check in, for waits which can take any amount of time such as for
+/// input or until the next upload. These can't be nested.
+pub async fn idle<F: Future>(task: Task, future: F) -> F::Output {
+    /// Resumes supervision even if the wait is cancelled.
+    struct Resume(Task);
+
+    impl Drop for Resume {
+        fn drop(&mut self) {
+            check_in(self.0);
+            IDLE.fetch_and(!self.0.bit(), Ordering::Relaxed);
+        }
+    }
+
+    IDLE.fetch_or(task.bit(), Ordering::Relaxed);
+    let _resume = Resume(task);
+    future.await
+}
+
+/// Feed the hardware watchdog for as long as every registered task keeps checking in.
+///
+/// [`init`](super::init) **must** be run first, this is meant to be spawned on a higher priority executor than the
+/// tasks it supervises.
+#[embassy_executor::task]
+#[allow(clippy::significant_drop_tightening)]
+pub async fn supervise() {
+    {
+        let mut watchdog = WATCHDOG_DEVICE.lock().await;
+        let Some(watchdog) = watchdog.as_mut() else {
+            log_warn!("The watchdog wasn't initialised, tasks won't be supervised");
+            return;
+        };
+
+        let scratch = watchdog.get_scratch(SCRATCH_INDEX);
+        if scratch & 0xffff_0000 == SCRATCH_MAGIC {
+            let missed = (scratch & 0xff) as u8;
+            MISSED_BEFORE_RESET.store(missed, Ordering::Relaxed);
+            log_warn!(
+                "Reset by the watchdog, missed the deadline: {}",
+                Tasks(missed)
+            );
+        }
+        watchdog.set_scratch(SCRATCH_INDEX, 0);
+
+        watchdog.pause_on_debug(true);
+        watchdog.start(HARDWARE_PERIOD);
+    }
+
+    loop {
+        Timer::after(CHECK_IN_INTERVAL).await;
+
+        let missed = overdue();
+        let mut watchdog = WATCHDOG_DEVICE.lock().await;
+        let Some(watchdog) = watchdog.as_mut() else {
+            continue;
+        };
+
+        if missed == 0 {
+            watchdog.feed();
+        } else {
+            // Stop feeding, the hardware watchdog resets the device once its period runs out.
+            watchdog.set_scratch(SCRATCH_INDEX, SCRATCH_MAGIC | u32::from(missed));
+            log_warn!("Tasks missed the watchdog deadline: {}", Tasks(missed));
+            return;
+        }
+    }
+}
+
+/// The registered tasks which aren't idle and haven't checked in within their deadline.
+fn overdue() -> u8 {
+    let supervised = REGISTERED.load(Ordering::Relaxed) & !IDLE.load(Ordering::Relaxed);
+    let now = now_millis();
+
+    Task::ALL
+        .into_iter()
+        .filter(|task| supervised & task.bit() != 0)
+        .filter(|task| {
+            let deadline = u32::try_from(task.deadline().as_millis()).unwrap_or(u32::MAX);
+            now.wrapping_sub(CHECK_INS[*task as usize].load(Ordering::Relaxed)) > deadline
+        })
+        .fold(0, |missed, task| missed | task.bit())
+}
+
+/// Milliseconds since boot, wrapping after about 49 days.
+#[allow(clippy::cast_possible_truncation)]
+fn now_millis() -> u32 {
+    Instant::now().as_millis() as u32
+}